@@ -0,0 +1,185 @@
+use std::{path::PathBuf, sync::Arc};
+
+use clap::{Parser, Subcommand};
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_util::codec::Framed;
+use useful::{
+    client::{handshake, Connection},
+    prelude::UniversalResult,
+    protocol::{Capability, ClientCodec, MAX_FRAME_SIZE, MIN_FRAME_SIZE},
+    tls::{add_system_certificates, load_certificates, load_tls},
+};
+
+use crate::profiles::Profile;
+
+const DEFAULT_PORT: u16 = 13360;
+/// Trusted when neither `--ca-file` nor `--system-roots` is given.
+const CERTIFICATE_PATH: &str = "../certificates/rootCA.crt";
+const CAPABILITIES: &[Capability] = &[Capability::Zstd, Capability::Gzip, Capability::Resume, Capability::Upload];
+
+#[derive(Debug, Parser)]
+#[command(about = "Browses, downloads from and uploads to an ftp-server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Server to connect to, as host or host:port [default: localhost]
+    #[arg(value_parser = parse_target)]
+    pub target: Option<Target>,
+    /// Saved connection to take the options not given here from
+    #[arg(short, long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// CA certificates (PEM) the server certificate has to be signed by
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<PathBuf>,
+    /// Also trust the CAs of the operating system
+    #[arg(long)]
+    pub system_roots: bool,
+    /// Name the server certificate has to be valid for, the host by default
+    #[arg(long, value_name = "NAME")]
+    pub server_name: Option<String>,
+    /// Certificate chain (PEM) to log in with, for servers that require one
+    #[arg(long, value_name = "FILE", requires = "key")]
+    pub certificate: Option<PathBuf>,
+    /// Private key (PEM) of --certificate
+    #[arg(long, value_name = "FILE", requires = "certificate")]
+    pub key: Option<PathBuf>,
+    /// User to log in as, without the UI the password is read from
+    /// MINI_FTP_PASSWORD or stdin
+    #[arg(short, long, value_name = "NAME")]
+    pub user: Option<String>,
+    /// Remote directory to go to after logging in
+    #[arg(long, value_name = "PATH")]
+    pub start_path: Option<String>,
+    /// Where downloads are saved by default instead of the current directory
+    #[arg(long, value_name = "DIR")]
+    pub download_dir: Option<PathBuf>,
+    /// Largest message the server may send in one frame, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_FRAME_SIZE, value_parser = parse_frame_size)]
+    pub max_frame_size: usize,
+}
+
+/// Run instead of the UI, for scripts.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List a remote directory, the start directory by default
+    Ls {
+        #[arg(default_value = ".")]
+        path: String,
+    },
+    /// Show the type, size and access of a remote entry
+    Stat { path: String },
+    /// Download a remote file
+    Get { remote: String, local: PathBuf },
+    /// Download a remote directory into a local one
+    Getdir { remote: String, local: PathBuf },
+    /// Upload a local file or directory
+    Put {
+        local: PathBuf,
+        remote: String,
+        /// Replace what is already there (entries that exist are skipped for directories otherwise)
+        #[arg(long)]
+        overwrite: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// `host`, `host:port`, `[ipv6]:port` or a bare IPv6 address.
+fn parse_target(address: &str) -> Result<Target, String> {
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().map_err(|_| format!("invalid port {port:?}"))?)
+        }
+        _ => (address, DEFAULT_PORT),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err("the host is missing".to_string());
+    }
+    Ok(Target { host: host.to_string(), port })
+}
+
+fn parse_frame_size(size: &str) -> Result<usize, String> {
+    match size.parse() {
+        Ok(size) if size >= MIN_FRAME_SIZE => Ok(size),
+        Ok(_) => Err(format!("has to be at least {MIN_FRAME_SIZE}")),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+impl Cli {
+    pub fn target(&self) -> Target {
+        self.target.clone().unwrap_or_else(|| Target { host: "localhost".to_string(), port: DEFAULT_PORT })
+    }
+    /// Fills in what was not given on the command line from `profile`.
+    pub fn apply(&mut self, profile: &Profile) -> Result<(), String> {
+        if self.target.is_none() {
+            self.target = match &profile.address {
+                Some(address) => Some(parse_target(address).map_err(|error| format!("Invalid address {address:?}: {error}"))?),
+                None => None,
+            };
+        }
+        let fields = [
+            (&mut self.server_name, &profile.server_name),
+            (&mut self.user, &profile.username),
+            (&mut self.start_path, &profile.start_path),
+        ];
+        for (field, saved) in fields {
+            if field.is_none() {
+                field.clone_from(saved);
+            }
+        }
+        for (field, saved) in [(&mut self.ca_file, &profile.ca_file), (&mut self.download_dir, &profile.download_dir)] {
+            if field.is_none() {
+                field.clone_from(saved);
+            }
+        }
+        Ok(())
+    }
+    /// Connects to the server and says hello, returning what both sides support.
+    pub async fn connect(&self) -> UniversalResult<(Connection, Vec<Capability>)> {
+        let mut roots = RootCertStore::empty();
+        if self.system_roots {
+            add_system_certificates(&mut roots)?;
+        }
+        if self.ca_file.is_some() || !self.system_roots {
+            let ca_file = self.ca_file.clone().unwrap_or_else(|| PathBuf::from(CERTIFICATE_PATH));
+            let certificates = load_certificates(&ca_file).map_err(|error| format!("Can't load {}: {error}", ca_file.display()))?;
+            roots.roots.extend(certificates.roots);
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let mut capabilities = CAPABILITIES.to_vec();
+        let client_configuration = match (&self.certificate, &self.key) {
+            (Some(chain_path), Some(key_path)) => {
+                let (chain, key) = load_tls(chain_path, key_path)?;
+                capabilities.push(Capability::ClientCertificate);
+                builder.with_client_auth_cert(chain, key)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(self.server_name.clone().unwrap_or_else(|| self.target().host))?;
+
+        let connector = TlsConnector::from(Arc::new(client_configuration));
+        let target = self.target();
+        let client = TcpStream::connect((target.host.as_str(), target.port)).await?;
+        let client = connector.connect(server_name, client).await?;
+        let mut client = Framed::new(client, ClientCodec::with_max_frame_size(self.max_frame_size));
+        let capabilities = handshake(&mut client, &capabilities).await?;
+        Ok((client, capabilities))
+    }
+}
@@ -0,0 +1,143 @@
+use std::io::{BufRead, Write};
+
+use futures::SinkExt;
+use useful::{
+    archive::Unpacker,
+    client::*,
+    compression,
+    prelude::UniversalResult,
+    protocol::{Capability, ErrorCode, Existing, RemoteError, Request, Response},
+};
+
+use crate::cli::{Cli, Command};
+
+/*
+Exit codes of the non-interactive commands:
+0 everything went fine, 1 the command failed (refused by the server, a broken
+transfer or a local file problem), 2 the command line was wrong (from clap),
+3 connecting or logging in failed.
+*/
+pub const FAILED: i32 = 1;
+pub const NOT_CONNECTED: i32 = 3;
+
+/// Where the password comes from when there is no terminal to ask on.
+const PASSWORD_VARIABLE: &str = "MINI_FTP_PASSWORD";
+
+/// Runs `command` without the UI, reporting errors on stderr, and returns the exit
+/// code.
+pub async fn run(cli: &Cli, command: &Command) -> i32 {
+    let (mut client, capabilities) = match connect(cli).await {
+        Ok(connected) => connected,
+        Err(error) => {
+            eprintln!("ftp-client: {error}");
+            return NOT_CONNECTED;
+        }
+    };
+    let result = execute(&mut client, &capabilities, command).await;
+    let _ = client.send(Request::Shutdown).await;
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("ftp-client: {error}");
+            FAILED
+        }
+    }
+}
+
+async fn connect(cli: &Cli) -> UniversalResult<(Connection, Vec<Capability>)> {
+    let (mut client, capabilities) = cli.connect().await?;
+    if capabilities.contains(&Capability::ClientCertificate) {
+        // The certificate already said who we are
+        match read_response(&mut client).await? {
+            Response::Listing { .. } => (),
+            other => return Err(unexpected_response(other)),
+        }
+    } else {
+        login(cli, &mut client).await?;
+    }
+    if let Some(start_path) = &cli.start_path {
+        // Relative paths in the command are taken from there
+        list_remote(&mut client, start_path).await?;
+    }
+    Ok((client, capabilities))
+}
+
+async fn login(cli: &Cli, client: &mut Connection) -> UniversalResult<()> {
+    let username = cli.user.clone().ok_or("Log in with --user <name>")?;
+    let password = match std::env::var(PASSWORD_VARIABLE) {
+        Ok(password) => password,
+        Err(_) => {
+            eprint!("Password for {username}: ");
+            std::io::stderr().flush()?;
+            let mut password = String::new();
+            std::io::stdin().lock().read_line(&mut password)?;
+            password.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    match send_request(client, Request::Login { username, password }).await? {
+        Response::Listing { .. } => Ok(()),
+        Response::Error { code, message } => Err(RemoteError { code, message }.into()),
+        other => Err(unexpected_response(other)),
+    }
+}
+
+async fn execute(client: &mut Connection, capabilities: &[Capability], command: &Command) -> UniversalResult<()> {
+    match command {
+        Command::Ls { path } => {
+            let (_, _, entries) = list_remote(client, path).await?;
+            for entry in entries.iter().filter(|entry| entry.path != "..") {
+                match entry.is_dir() {
+                    true => println!("{}/", entry.name()),
+                    false => println!("{}", entry.name()),
+                }
+            }
+        }
+        Command::Stat { path } => {
+            match send_request(client, Request::FileInfo(path.clone())).await? {
+                Response::FileInfo { is_file, size, access } => {
+                    println!("type: {}", if is_file { "file" } else { "directory" });
+                    println!("size: {size}");
+                    println!("access: {}", access.name());
+                }
+                Response::Error { code, message } => return Err(RemoteError { code, message }.into()),
+                other => return Err(unexpected_response(other)),
+            }
+        }
+        Command::Get { remote, local } => {
+            let resume = capabilities.contains(&Capability::Resume);
+            download_file(client, remote, local, resume).await?;
+        }
+        Command::Getdir { remote, local } => {
+            let created = !local.exists();
+            if created {
+                std::fs::create_dir(local)?;
+            }
+            let mut unpacker = Unpacker::new(local.clone(), Existing::Overwrite);
+            let result = match download(client, Request::SaveDir(remote.clone()), &mut unpacker).await {
+                Ok(_) => unpacker.finish().map_err(Into::into),
+                Err(error) => {
+                    let _ = unpacker.finish();
+                    Err(error)
+                }
+            };
+            if result.is_err() && created {
+                let _ = std::fs::remove_dir_all(local);
+            }
+            result?;
+        }
+        Command::Put { local, remote, overwrite } => {
+            if !capabilities.contains(&Capability::Upload) {
+                return Err(RemoteError { code: ErrorCode::InvalidRequest, message: "The server does not accept uploads".to_string() }.into());
+            }
+            let negotiated = compression::negotiated(capabilities);
+            if local.is_dir() {
+                let existing = if *overwrite { Existing::Overwrite } else { Existing::Skip };
+                upload_dir(client, local, remote, existing, negotiated).await?;
+            } else {
+                let compression = compression::for_file(local, negotiated);
+                upload_file(client, local, remote, *overwrite, compression).await?;
+            }
+        }
+    }
+    Ok(())
+}
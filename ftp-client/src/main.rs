@@ -1,22 +1,40 @@
-#![allow(clippy::unused_io_amount, clippy::implicit_saturating_sub)]
+#![allow(clippy::implicit_saturating_sub, clippy::collapsible_match)]
 use crossterm::{terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen}, ExecutableCommand};
 use ratatui::{
     crossterm::event::{self, KeyCode, KeyEventKind}, layout::{Constraint, Layout}, style::{Color, Stylize}, widgets::{Block, Borders, Paragraph}, DefaultTerminal
 };
-use rustls::pki_types::ServerName;
+use clap::Parser;
+use futures::SinkExt;
 use std::{
-    env::current_dir, ffi::OsStr, io::Write, path::{Path, PathBuf}, sync::Arc
+    env::current_dir, ffi::OsStr, path::{Path, PathBuf}
 };
-use tar::Archive;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::rustls::ClientConfig;
-use tokio_rustls::TlsConnector;
-use useful::{client::*, prelude::*};
-const DESTINATION_ADDRESS: &str = "0.0.0.0:13360";
-const CERTIFICATE_PATH: &str = "../certificates/rootCA.crt";
+use useful::{archive::Unpacker, client::*, compression, prelude::*, protocol::{Access, Capability, Entry, ErrorCode, Existing, Request, Response}};
+
+mod cli;
+mod commands;
+mod profiles;
+use cli::Cli;
+use profiles::{pick_profile, Profiles};
+
 #[tokio::main]
 async fn main() -> UniversalResult<()> {
+    let mut cli = Cli::parse();
+    let profiles = match Profiles::load() {
+        Ok(profiles) => profiles,
+        Err(error) => {
+            eprintln!("ftp-client: {error}");
+            std::process::exit(2);
+        }
+    };
+    if let Some(name) = &cli.profile {
+        if let Err(error) = profiles.get(name).cloned().and_then(|profile| cli.apply(&profile)) {
+            eprintln!("ftp-client: {error}");
+            std::process::exit(2);
+        }
+    }
+    if let Some(command) = &cli.command {
+        std::process::exit(commands::run(&cli, command).await);
+    }
     if let Err(err) = color_eyre::install() {
         println!("Couldn't install color_eyre: {err}");
         println!("Will be using custom panic hook, but it is not so accurate!");
@@ -36,7 +54,7 @@ async fn main() -> UniversalResult<()> {
     let mut terminal = ratatui::Terminal::new(backend)?;
     // let mut terminal = ratatui::init();
     terminal.clear()?;
-    if let Err(error) = run(&mut terminal).await {
+    if let Err(error) = run(&mut terminal, cli, &profiles).await {
         terminal.clear()?;
         block_to_continue(Paragraph::new(format!("Error: {error} (press q to exit)")).blue().on_red(), &mut terminal)?;
     };
@@ -44,25 +62,20 @@ async fn main() -> UniversalResult<()> {
     disable_raw_mode()?;
     Ok(())
 }
-async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
-    let certificates = load_certificates(CERTIFICATE_PATH)?;
-    let client_configuration = ClientConfig::builder()
-        .with_root_certificates(certificates)
-        .with_no_client_auth();
-
+async fn run(terminal: &mut DefaultTerminal, mut cli: Cli, profiles: &Profiles) -> UniversalResult<()> {
+    // Nothing on the command line says where to go, maybe a profile does
+    if cli.profile.is_none() && cli.target.is_none() && !profiles.profiles.is_empty() {
+        if let Some(profile) = pick_profile(terminal, profiles)? {
+            cli.apply(profile)?;
+        }
+    }
     terminal.draw(|frame| {
         frame.render_widget(
-            Paragraph::new(format!("Connecting to {DESTINATION_ADDRESS}\n")).centered(),
+            Paragraph::new(format!("Connecting to {}\n", cli.target())).centered(),
             frame.area(),
         );
     })?;
-    let connector = TlsConnector::from(Arc::new(client_configuration));
-    let mut client = {
-        let client = TcpStream::connect(DESTINATION_ADDRESS).await?;
-        connector
-            .connect(ServerName::try_from("localhost")?, client)
-            .await?
-    };
+    let (mut client, capabilities) = cli.connect().await?;
 
     terminal.draw(|frame| {
         frame.render_widget(
@@ -71,39 +84,65 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
         );
     })?;
 
-    let mut data: Vec<u8> = vec![0; calculate_packet_size(&mut client).await?];
-    client.read_exact(&mut data).await?;
-    let mut entries = unwrap_empty_string(String::from_utf8(data).unwrap(), "\r");
+    let (mut remote_path, mut remote_access, mut entries) = if capabilities.contains(&Capability::ClientCertificate) {
+        // The certificate already said who we are
+        match read_response(&mut client).await? {
+            Response::Listing { path, access, entries } => (path, access, entries),
+            other => return Err(unexpected_response(other)),
+        }
+    } else { loop {
+        let username = draw_input_field(terminal, Some("Username ".to_string()), cli.user.clone())?;
+        let password = draw_password_field(terminal, Some(format!("Password for {username} ")))?;
+        match send_request(&mut client, Request::Login { username, password }).await? {
+            Response::Listing { path, access, entries } => break (path, access, entries),
+            Response::Error { code, message } => draw_error_popup(terminal, code, &message)?,
+            other => return Err(unexpected_response(other)),
+        }
+    } };
+    if let Some(start_path) = &cli.start_path {
+        match list_remote(&mut client, start_path).await {
+            Ok(listing) => (remote_path, remote_access, entries) = listing,
+            Err(error) => draw_remote_error(terminal, error)?,
+        }
+    }
+    let download_dir = match &cli.download_dir {
+        Some(download_dir) => download_dir.clone(),
+        None => current_dir()?,
+    };
     let mut folder_history: Vec<String> = vec![];
 
     let mut currently_selected: usize = 0;
     let mut pointing_to_start: usize = 0; // '..' is always first
 
     loop {
-        let current_entry = entries.get(currently_selected).unwrap().to_str().unwrap();
+        let current_entry = match entries.get(currently_selected) {
+            Some(entry) => entry.clone(),
+            // Only the served root can be empty, there is nothing to select there
+            None => Entry::dir("."),
+        };
         terminal.clear()?;
-        print_directory(terminal, &entries, currently_selected, pointing_to_start)?;
+        print_directory(terminal, &remote_path, remote_access, &entries, currently_selected, pointing_to_start)?;
         if let event::Event::Key(key) = event::read()? {
             if key.kind == KeyEventKind::Press {
                 match key.code {
                     KeyCode::Char(' ') => {
                         loop {
-                            let (filetype, filesize, path) = {
-                                let path = {
-                                let path = current_entry.strip_prefix("DIR_").unwrap_or_else(|| current_entry.strip_prefix("FILE_").unwrap());
-                                let packet = build_packet(format!("FILEINFO_{path}"), '\r');
-                                client.write(&packet).await?;
-                                path
-                                };
-                                let mut data = vec![0u8; calculate_packet_size(&mut client).await?];
-                                client.read_exact(&mut data).await?;
-                                let data_converted = String::from_utf8(data)?;
-                                let data_converted = data_converted.split("\r").map(|d| d.parse::<u64>().unwrap()).collect::<Vec<u64>>();
-                                (data_converted[0], data_converted[1], path)
-
+                            let path = current_entry.path.as_str();
+                            let (is_file, filesize, access) = match send_request(&mut client, Request::FileInfo(path.to_string())).await? {
+                                Response::FileInfo { is_file, size, access } => (is_file, size, access),
+                                Response::Error { code, message } => {
+                                    draw_error_popup(terminal, code, &message)?;
+                                    break
+                                }
+                                other => return Err(unexpected_response(other)),
                             };
-                            let filetypeparagraph = Paragraph::new(if filetype == 1 {"Entrytype: File"} else {"Entrytype: Folder"}).centered();
+                            let filetypeparagraph = Paragraph::new(if is_file {"Entrytype: File"} else {"Entrytype: Folder"}).centered();
                             let filesizeparagraph = Paragraph::new(format!("Entry size: {:.2} KB", filesize / 1024)).centered();
+                            let accessparagraph = Paragraph::new(match access {
+                                Access::Browse => "Access: browse only",
+                                Access::Read => "Access: read-only",
+                                Access::Write => "Access: read-write",
+                            }).centered();
 
                             terminal.draw(|frame| {
                                 let center = {
@@ -121,12 +160,14 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                     /*
                                     Entry type: Folder/File
                                     Entry size:  ... KB
+                                    Access: ...
                                      */
-                                    Layout::new(ratatui::layout::Direction::Vertical, vec![Constraint::Percentage(100 / 3), Constraint::Percentage(100 / 3), Constraint::Percentage(100/3)]).split(vertical_mid)
+                                    Layout::new(ratatui::layout::Direction::Vertical, vec![Constraint::Percentage(25), Constraint::Percentage(25), Constraint::Percentage(25), Constraint::Percentage(25)]).split(vertical_mid)
                                 };
                                 frame.render_widget(Paragraph::new(path), center[0]);
                                 frame.render_widget(filetypeparagraph, center[1]);
                                 frame.render_widget(filesizeparagraph, center[2]);
+                                frame.render_widget(accessparagraph, center[3]);
                                 
 
                             })?;
@@ -138,17 +179,17 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                         }
                     },
                     KeyCode::Char('s') => {
+                        if current_entry.access < Access::Read {
+                            draw_error_popup(terminal, ErrorCode::PermissionDenied, &format!("You may not save {}", current_entry.name()))?;
+                            continue;
+                        }
                         let path = {
-                            if !current_entry.starts_with("FILE_") {
-                                let current_entry = format!(
-                                    "SAVEDIR_{}",
-                                    current_entry.strip_prefix("DIR_").unwrap()
-                                );
+                            if current_entry.is_dir() {
                                 let default_val = {
-                                    let mut current = current_dir()?;
+                                    let mut current = download_dir.clone();
                                     current.push(format!(
                                         "copied_{}",
-                                        Path::new(&current_entry)
+                                        Path::new(&current_entry.path)
                                             .file_name()
                                             .unwrap()
                                             .to_str()
@@ -157,7 +198,7 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                     current.to_str().unwrap().to_string()
                                 };
 
-                                let mut path_to_receive = PathBuf::from(draw_input_field(
+                                let path_to_receive = PathBuf::from(draw_input_field(
                                     terminal,
                                     Some("Enter path to save folder ".to_string()),
                                     Some(default_val),
@@ -195,20 +236,20 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                         frame.area(),
                                     );
                                 })?;
-                                let packet = build_packet(current_entry, '\r');
-                                client.write(&packet).await?;
-                                let mut tarbuffer =
-                                    vec![0u8; calculate_packet_size(&mut client).await?];
-                                client.read_exact(&mut tarbuffer).await?;
                                 std::fs::create_dir(&path_to_receive)?;
-                                path_to_receive.push("filetar.tar");
-                                let mut tarfile = std::fs::File::create(&path_to_receive)?;
-                                tarfile.write(&tarbuffer)?;
-                                tarfile.flush()?;
-                                let mut archive =
-                                    Archive::new(std::fs::File::open(&path_to_receive).unwrap());
-                                path_to_receive.pop();
-                                archive.unpack(&path_to_receive)?;
+                                let mut unpacker = Unpacker::new(path_to_receive.clone(), Existing::Overwrite);
+                                let result = match download(&mut client, Request::SaveDir(current_entry.path.clone()), &mut unpacker).await {
+                                    Ok(_) => unpacker.finish().map_err(Into::into),
+                                    Err(error) => {
+                                        let _ = unpacker.finish();
+                                        Err(error)
+                                    }
+                                };
+                                if let Err(error) = result {
+                                    std::fs::remove_dir_all(&path_to_receive)?;
+                                    draw_remote_error(terminal, error)?;
+                                    continue;
+                                }
                                 block_to_continue(
                                     Paragraph::new(format!(
                                         "Unpacked at location {}",
@@ -221,9 +262,9 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                 )?;
                                 continue;
                             }
-                            let mut default_val = current_dir().unwrap();
+                            let mut default_val = download_dir.clone();
                             default_val.push(
-                                Path::new(current_entry.strip_prefix("FILE_").unwrap())
+                                Path::new(&current_entry.path)
                                     .file_name()
                                     .unwrap(),
                             );
@@ -241,23 +282,163 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                             )?;
                             continue;
                         }
-                        let packet = build_packet(current_entry.to_string(), '\r');
-                        client.write(&packet).await?;
-                        let mut got: Vec<u8> = vec![0; calculate_packet_size(&mut client).await?];
-                        client.read_exact(&mut got).await?;
-
-                        std::fs::write(path, got)?;
+                        let path = PathBuf::from(path);
+                        let resume = capabilities.contains(&Capability::Resume);
+                        let status = match std::fs::metadata(part_path(&path)) {
+                            Ok(partial) if resume => format!("Resuming download at {} KB...", partial.len() / 1024),
+                            _ => "Downloading...".to_string(),
+                        };
+                        terminal.draw(|frame| {
+                            frame.render_widget(Paragraph::new(status).centered().yellow(), frame.area());
+                        })?;
+                        if let Err(error) = download_file(&mut client, &current_entry.path, &path, resume).await {
+                            draw_remote_error(terminal, error)?;
+                        }
                     }
-                    KeyCode::Char('q') | KeyCode::Esc => {
-                        {
-                            let packet = build_packet("SHUTDOWN".into(), '\r');
-                            client.write(&packet).await?;
+                    KeyCode::Char('u') => {
+                        if !capabilities.contains(&Capability::Upload) {
+                            draw_error_popup(terminal, "Unsupported", "The server does not accept uploads")?;
+                            continue;
                         }
+                        if remote_access != Access::Write {
+                            draw_error_popup(terminal, ErrorCode::PermissionDenied, &format!("{remote_path} is read-only for you"))?;
+                            continue;
+                        }
+                        let local = PathBuf::from(draw_input_field(
+                            terminal,
+                            Some("Enter local file or folder to upload ".to_string()),
+                            Some(current_dir()?.to_string_lossy().to_string()),
+                        )?);
+                        let name = match local.file_name().and_then(OsStr::to_str) {
+                            Some(name) if local.exists() => name.to_string(),
+                            _ => {
+                                block_to_continue(Paragraph::new("Not a file or folder... (Press anything to escape)"), terminal)?;
+                                continue;
+                            }
+                        };
+                        let exists = entries.iter().any(|entry| Path::new(&entry.path).file_name().and_then(OsStr::to_str) == Some(name.as_str()));
+                        let existing = if !exists {
+                            Existing::Overwrite
+                        } else {
+                            let question = if local.is_dir() {
+                                format!("There is already a {name} on the server, what should I do with entries that are in both? (press 'o' to overwrite, 's' to skip them or 'n' to cancel)")
+                            } else {
+                                format!("There is already a {name} on the server, should I overwrite it? (press 'y' for yes or 'n' for no)")
+                            };
+                            let existing = loop {
+                                terminal.draw(|frame| {
+                                    frame.render_widget(Paragraph::new(question.as_str()).centered(), frame.area());
+                                })?;
+                                if let event::Event::Key(e) = event::read()? {
+                                    if e.kind == KeyEventKind::Press {
+                                        break match e.code {
+                                            KeyCode::Char('y') | KeyCode::Char('o') => Some(Existing::Overwrite),
+                                            KeyCode::Char('s') if local.is_dir() => Some(Existing::Skip),
+                                            _ => None,
+                                        };
+                                    }
+                                }
+                            };
+                            match existing {
+                                Some(existing) => existing,
+                                None => continue,
+                            }
+                        };
+                        terminal.clear()?;
+                        terminal.draw(|frame| {
+                            frame.render_widget(Paragraph::new("Uploading please wait...").centered().yellow(), frame.area());
+                        })?;
+                        let result = if local.is_dir() {
+                            upload_dir(&mut client, &local, &name, existing, compression::negotiated(&capabilities)).await
+                        } else {
+                            let compression = compression::for_file(&local, compression::negotiated(&capabilities));
+                            upload_file(&mut client, &local, &name, exists, compression).await
+                        };
+                        let sent = match result {
+                            Ok(sent) => sent,
+                            Err(error) => {
+                                draw_remote_error(terminal, error)?;
+                                continue;
+                            }
+                        };
+                        (remote_path, remote_access, entries) = match list_remote(&mut client, ".").await {
+                            Ok(listing) => listing,
+                            Err(error) => {
+                                draw_remote_error(terminal, error)?;
+                                continue;
+                            }
+                        };
+                        currently_selected = currently_selected.min(entries.len().saturating_sub(1));
+                        block_to_continue(
+                            Paragraph::new(format!("Uploaded {name} ({} KB)", sent / 1024))
+                                .bold()
+                                .centered()
+                                .fg(Color::Green),
+                            terminal,
+                        )?;
+                    }
+                    KeyCode::Char('m') | KeyCode::Char('r') | KeyCode::Char('d') => {
+                        let name = Path::new(&current_entry.path).file_name().and_then(OsStr::to_str).unwrap_or("").to_string();
+                        let allowed = remote_access == Access::Write && (key.code == KeyCode::Char('m') || current_entry.access == Access::Write);
+                        if !allowed {
+                            let changed = if key.code == KeyCode::Char('m') { remote_path.as_str() } else { current_entry.path.as_str() };
+                            draw_error_popup(terminal, ErrorCode::PermissionDenied, &format!("{changed} is read-only for you"))?;
+                            continue;
+                        }
+                        let request = match key.code {
+                            KeyCode::Char('m') => {
+                                let path = draw_input_field(terminal, Some("Enter name of the new remote folder ".to_string()), None)?;
+                                if path.is_empty() {
+                                    continue;
+                                }
+                                Request::Mkdir(path)
+                            }
+                            KeyCode::Char('r') => {
+                                let to = draw_input_field(terminal, Some(format!("Enter new name or path for {name} ")), Some(name.clone()))?;
+                                if to.is_empty() || to == name {
+                                    continue;
+                                }
+                                Request::Rename { from: current_entry.path.clone(), to }
+                            }
+                            _ => {
+                                let question = if current_entry.is_dir() {
+                                    format!("Should I delete the folder {name} on the server and everything in it? (press 'y' for yes or 'n' for no)")
+                                } else {
+                                    format!("Should I delete {name} on the server? (press 'y' for yes or 'n' for no)")
+                                };
+                                let delete = loop {
+                                    terminal.draw(|frame| {
+                                        frame.render_widget(Paragraph::new(question.as_str()).centered(), frame.area());
+                                    })?;
+                                    if let event::Event::Key(e) = event::read()? {
+                                        if e.kind == KeyEventKind::Press {
+                                            break e.code == KeyCode::Char('y');
+                                        }
+                                    }
+                                };
+                                if !delete {
+                                    continue;
+                                }
+                                Request::Delete { path: current_entry.path.clone(), recursive: current_entry.is_dir() }
+                            }
+                        };
+                        match change_remote(&mut client, request).await {
+                            Ok(listing) => (remote_path, remote_access, entries) = listing,
+                            Err(error) => {
+                                draw_remote_error(terminal, error)?;
+                                continue;
+                            }
+                        }
+                        currently_selected = currently_selected.min(entries.len().saturating_sub(1));
+                    }
+                    KeyCode::Char('q') | KeyCode::Esc => {
+                        client.send(Request::Shutdown).await?;
 
                         return Ok(());
                     }
                     KeyCode::Up | KeyCode::Char('k') => {
-                        if currently_selected == 0 && get_screen_size().1 > entries.len() as u16 {
+                        // The breadcrumb and the keys take a line each
+                        if currently_selected == 0 && get_screen_size().1 - 2 > entries.len() as u16 {
                             currently_selected = entries.len();
                         }
 
@@ -272,27 +453,24 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
 
                     },
                     KeyCode::Enter | KeyCode::Right => {
-                        let packet = build_packet(current_entry.to_string(), '\r');
-                        client.write(&packet).await?;
-                        if let Some(current_entry) = current_entry.strip_prefix("FILE_") {
-                            let filelen: usize = {
-                                let mut buffer = String::new();
-                                let mut current_char: [u8;1] = [0];
-                                while current_char[0] != b'\r' {
-                                    client.read(&mut current_char).await?;
-                                    buffer.push(current_char[0] as char);
-                                }
-                                if buffer == "fileisbinary\r" {
-                                    block_to_continue(Paragraph::new(format!("I can't show {current_entry} because it is a binary file sorry :(")).centered().bg(Color::Red), terminal)?;
+                        if !current_entry.is_dir() {
+                            if current_entry.access < Access::Read {
+                                draw_error_popup(terminal, ErrorCode::PermissionDenied, &format!("You may not read {}", current_entry.name()))?;
+                                continue
+                            }
+                            let current_entry = current_entry.path.as_str();
+                            let mut content: Vec<u8> = vec![];
+                            if let Err(error) = download(&mut client, Request::File(current_entry.to_string()), &mut content).await {
+                                draw_remote_error(terminal, error)?;
+                                continue
+                            }
+                            let filecontent_as_str = match String::from_utf8(content) {
+                                Ok(content) => content,
+                                Err(_) => {
+                                    block_to_continue(Paragraph::new(format!("I can't show {current_entry} because it is a binary file sorry :( (press 's' on it in the listing to save it)")).centered().bg(Color::Red), terminal)?;
                                     continue
                                 }
-                                buffer.pop();
-                                buffer.parse().unwrap()
                             };
-                            let mut filecontent =
-                                vec![0; filelen];
-                            client.read_exact(&mut filecontent).await?;
-                            let filecontent_as_str = String::from_utf8(filecontent)?;
                             let mut pointer_to_end: u16 = 0;
                             let mut pointer_to_start: u16 = 0;
                             let screen_max_y: u16 = get_screen_size().1;
@@ -344,7 +522,7 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                                     filename.file_name().unwrap().to_str().unwrap();
                                                 terminal.clear()?;
                                                 let path = {
-                                                    let mut default_val = current_dir().unwrap();
+                                                    let mut default_val = download_dir.clone();
                                                     default_val.push(Path::new(&filename));
                                                     let path = PathBuf::from(
                                                         draw_input_field(
@@ -378,7 +556,7 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                                                 )?;
                                             }
                                             KeyCode::Char(key) => {
-                                                if key.is_digit(10) {
+                                                if key.is_ascii_digit() {
                                                     jump_to_buffer.push(key);
                                                     statustext = format!("{jump_to_buffer} (press g to jump)");
                                                 }
@@ -392,36 +570,38 @@ async fn run(terminal: &mut DefaultTerminal) -> UniversalResult<()> {
                             }
                         } 
                         else {
-                            if entries.len() > 1 {
-                                if entries[1].starts_with("FILE_") {
-                                    let modified = entries[1].parent().unwrap().to_str().unwrap().to_string();
-                                    let modified = modified.strip_prefix("FILE_").unwrap();
-                                    folder_history.push(format!("DIR_{modified}"));
-                                }
-                                else {
-                                folder_history.push(entries[1].parent().unwrap().to_str().unwrap().to_string());
+                            let (new_path, new_access, new_entries) = match send_request(&mut client, Request::Dir(current_entry.path.clone())).await? {
+                                Response::Listing { path, access, entries } => (path, access, entries),
+                                Response::Error { code, message } => {
+                                    draw_error_popup(terminal, code, &message)?;
+                                    continue
                                 }
-                            }
-                            let mut directories =
-                                vec![0u8; calculate_packet_size(&mut client).await?];
-                            client.read_exact(&mut directories).await?;
-                            entries = unwrap_empty_string(String::from_utf8(directories)?, "\r");
+                                other => return Err(unexpected_response(other)),
+                            };
+                            folder_history.push(std::mem::replace(&mut remote_path, new_path));
+                            remote_access = new_access;
+                            entries = new_entries;
                             currently_selected = 0;
                         }
                     },
                     KeyCode::Left => {
                         if let Some(last) = folder_history.pop() {
-                            let packet = build_packet(last, '\r');
-                            client.write(&packet).await?;
-                            let mut content = vec![0u8; calculate_packet_size(&mut client).await?];
-                            client.read_exact(&mut content).await?;
-                            entries = unwrap_empty_string(String::from_utf8(content)?, "\r");
+                            (remote_path, remote_access, entries) = match send_request(&mut client, Request::Dir(last)).await? {
+                                Response::Listing { path, access, entries } => (path, access, entries),
+                                Response::Error { code, message } => {
+                                    draw_error_popup(terminal, code, &message)?;
+                                    continue
+                                }
+                                other => return Err(unexpected_response(other)),
+                            };
 
                         }
                     },
                     KeyCode::Down | KeyCode::Char('j') => {
-                        currently_selected = (currently_selected + 1) % entries.len();
-                        if entries.len() > get_screen_size().1 as usize  && currently_selected + 1 > (get_screen_size().1 as usize) {
+                        currently_selected = (currently_selected + 1) % entries.len().max(1);
+                        // The breadcrumb and the keys take a line each
+                        let listing_height = get_screen_size().1 as usize - 2;
+                        if entries.len() > listing_height && currently_selected + 1 > listing_height {
                             pointing_to_start += 1;
                         }
 
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+
+use ratatui::{
+    crossterm::event::{self, KeyCode, KeyEventKind}, layout::{Constraint, Direction, Layout}, style::{Color, Style, Stylize}, text::{Line, Text}, widgets::Paragraph, DefaultTerminal
+};
+use serde::Deserialize;
+use useful::prelude::UniversalResult;
+
+/*
+Profiles live in `<config dir>/mini-ftp/config.toml` (`~/.config/mini-ftp/config.toml`
+on Linux), one table per profile:
+
+    [profiles.work]
+    address = "files.example.com:13360"
+    ca_file = "work-ca.crt"
+    username = "alice"
+    download_dir = "~/Downloads"
+    start_path = "/projects"
+
+Every field is optional and stands for the command line option of the same name,
+options given on the command line win. Relative paths are taken from the
+directory the file is in, `~/` is the home directory.
+*/
+/// The saved connections, by name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profiles {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// host or host:port
+    pub address: Option<String>,
+    pub ca_file: Option<PathBuf>,
+    pub server_name: Option<String>,
+    pub username: Option<String>,
+    /// Where downloads are saved unless another place is typed in.
+    pub download_dir: Option<PathBuf>,
+    /// Remote directory to go to after logging in.
+    pub start_path: Option<String>,
+}
+
+impl Profiles {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|directory| directory.join("mini-ftp").join("config.toml"))
+    }
+    /// Reads the profiles file, there are no profiles when it does not exist.
+    pub fn load() -> Result<Self, String> {
+        let Some(path) = Self::path() else {
+            return Ok(Profiles::default());
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Profiles::default()),
+            Err(error) => return Err(format!("Can't read {}: {error}", path.display())),
+        };
+        let mut profiles: Profiles = toml::from_str(&content).map_err(|error| format!("Invalid config {}: {error}", path.display()))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for profile in profiles.profiles.values_mut() {
+            for path in [&mut profile.ca_file, &mut profile.download_dir].into_iter().flatten() {
+                *path = resolve(directory, path);
+            }
+        }
+        Ok(profiles)
+    }
+    pub fn get(&self, name: &str) -> Result<&Profile, String> {
+        self.profiles.get(name).ok_or_else(|| match Self::path() {
+            Some(path) => format!("There is no profile {name:?} in {}", path.display()),
+            None => format!("There is no profile {name:?}"),
+        })
+    }
+}
+
+fn resolve(directory: &Path, path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => directory.join(path),
+    }
+}
+
+/// Lets the user pick one of `profiles` to connect with, `None` when they would
+/// rather go on with the command line options.
+pub fn pick_profile<'a>(terminal: &mut DefaultTerminal, profiles: &'a Profiles) -> UniversalResult<Option<&'a Profile>> {
+    let count = profiles.profiles.len();
+    let mut selected = 0;
+    loop {
+        let lines: Vec<Line> = profiles.profiles.iter().enumerate().map(|(index, (name, profile))| {
+            let address = profile.address.as_deref().unwrap_or("localhost");
+            let text = match &profile.username {
+                Some(username) => format!("{name}  ({username}@{address})"),
+                None => format!("{name}  ({address})"),
+            };
+            let style = if index == selected { Style::default().bg(Color::LightGreen).fg(Color::White) } else { Style::default() };
+            Line::styled(text, style)
+        }).collect();
+        terminal.draw(|frame| {
+            let layout = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)])
+                .split(frame.area());
+            frame.render_widget(Paragraph::new("Connect with profile").bold().fg(Color::Cyan), layout[0]);
+            frame.render_widget(Paragraph::new(Text::from(lines)), layout[1]);
+            frame.render_widget(Paragraph::new("enter connect  esc use the command line"), layout[2]);
+        })?;
+        if let event::Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                match key.code {
+                    KeyCode::Enter | KeyCode::Right => return Ok(profiles.profiles.values().nth(selected)),
+                    KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+                    KeyCode::Up | KeyCode::Char('k') => selected = (selected + count - 1) % count,
+                    KeyCode::Down | KeyCode::Char('j') => selected = (selected + 1) % count,
+                    _ => (),
+                }
+            }
+        }
+    }
+}
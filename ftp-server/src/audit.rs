@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tracing::info;
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{filter::filter_fn, fmt, Layer, Registry};
+
+use crate::config::{Audit, Rotation};
+
+/*
+Every request that reads from the served tree (DIR, FILEINFO, FILE, READRANGE and
+SAVEDIR) leaves one JSON line in `audit.<date>.log`:
+
+    {"timestamp":"…","op":"FILE","path":"/reports/q3.pdf","requested":"../q3.pdf",
+     "bytes":48213,"result":"ok","duration_ms":12,"span":{"peer":"…","session":4,…}}
+
+`path` is the entry the request resolved to, with every symlink followed, as seen
+from the served root. It is missing when the request named nothing that exists in
+the served tree, `requested` always holds what the client sent. `result` is `ok`,
+the error code the request was refused with, or `disconnected` when the connection
+broke during it. `bytes` is the content sent, before compression, also when a
+transfer broke off halfway. The records are events for the `audit` target, which only the layer
+made here writes.
+*/
+const TARGET: &str = "audit";
+
+/// The layer writing the audit log, the guard has to be kept for as long as
+/// records should reach the file.
+pub fn layer(
+    audit: &Audit,
+) -> Result<(Box<dyn Layer<Registry> + Send + Sync>, WorkerGuard), String> {
+    let rotation = match audit.rotation {
+        Rotation::Hourly => rolling::Rotation::HOURLY,
+        Rotation::Daily => rolling::Rotation::DAILY,
+        Rotation::Never => rolling::Rotation::NEVER,
+    };
+    let mut builder = rolling::RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix("audit")
+        .filename_suffix("log");
+    if let Some(keep) = audit.keep {
+        builder = builder.max_log_files(keep);
+    }
+    let appender = builder.build(&audit.directory).map_err(|error| {
+        format!(
+            "Can't write the audit log to {}: {error}",
+            audit.directory.display()
+        )
+    })?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    let layer = fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(false)
+        .with_target(false)
+        .with_level(false)
+        .with_writer(writer)
+        // The spans are needed for the connection the record belongs to
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() || metadata.target() == TARGET
+        }));
+    Ok((Box::new(layer), guard))
+}
+
+/// Keeps audit records out of the other layers.
+pub fn exclude<S>() -> impl tracing_subscriber::layer::Filter<S> {
+    filter_fn(|metadata| metadata.target() != TARGET)
+}
+
+/// Records a request that read from the served tree, in the span of its connection.
+pub fn record(
+    op: &str,
+    path: Option<&str>,
+    requested: Option<&str>,
+    bytes: u64,
+    result: &str,
+    duration: Duration,
+) {
+    info!(
+        target: TARGET,
+        op,
+        path,
+        requested,
+        bytes,
+        result,
+        duration_ms = duration.as_millis() as u64
+    );
+}
@@ -0,0 +1,357 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use useful::protocol::{MAX_FRAME_SIZE, MIN_FRAME_SIZE};
+
+/*
+Settings come from the TOML file given with `--config`, anything not in it keeps
+its default, and command line options win over both. Relative paths in the file
+are taken from the directory the file is in, so the server can be started from
+anywhere.
+*/
+/// Everything the server can be told.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to listen on.
+    pub bind: Vec<SocketAddr>,
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// When set, clients need a certificate signed by this CA and are logged in as
+    /// the common name of its subject instead of with a password.
+    pub client_ca: Option<PathBuf>,
+    /// The directory that is served.
+    pub root: PathBuf,
+    pub credentials: PathBuf,
+    /// Who may read and change what, has to exist when it is set. Otherwise
+    /// `permissions` next to `credentials` is read if it is there, and everyone may
+    /// do everything if not.
+    pub permissions: Option<PathBuf>,
+    pub limits: Limits,
+    pub logging: Logging,
+    pub audit: Audit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 13360))],
+            certificate: PathBuf::from("../certificates/server_chain.pem"),
+            private_key: PathBuf::from("../certificates/server.key"),
+            client_ca: None,
+            root: PathBuf::from("."),
+            credentials: PathBuf::from("../credentials"),
+            permissions: None,
+            limits: Limits::default(),
+            logging: Logging::default(),
+            audit: Audit::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Wrong logins a client gets before it is disconnected.
+    pub max_login_attempts: usize,
+    /// Connections served at the same time, more are turned away.
+    pub max_connections: usize,
+    /// Connections served at the same time from one address.
+    pub max_connections_per_ip: usize,
+    /// Seconds a client gets to finish the TLS handshake.
+    pub handshake_timeout_secs: u64,
+    /// Seconds the server waits for the client to send something, or to take some
+    /// of what is being sent to it, before closing the connection.
+    pub idle_timeout_secs: u64,
+    /// Largest message a client may send in one frame, in bytes. Clients that
+    /// announce a bigger one are disconnected.
+    pub max_frame_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_login_attempts: 3,
+            max_connections: 256,
+            max_connections_per_ip: 16,
+            handshake_timeout_secs: 10,
+            idle_timeout_secs: 300,
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+/// How much the server tells about what it is doing, each level including the
+/// ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    /// Requests that were refused and clients that misbehaved
+    Warn,
+    #[default]
+    Info,
+    /// Every request as it comes in
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One readable line per event
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Who read what, kept apart from the log.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audit {
+    pub enabled: bool,
+    /// Where the audit files go, a new one is started every `rotation`.
+    pub directory: PathBuf,
+    pub rotation: Rotation,
+    /// How many audit files to keep, all of them when not set.
+    pub keep: Option<usize>,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Audit {
+            enabled: true,
+            directory: PathBuf::from("../audit"),
+            rotation: Rotation::default(),
+            keep: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Serves a directory over TLS to ftp-client")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file to read the settings from
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Adds a user to the credentials file, asking for the password on stdin
+    Adduser { name: String },
+}
+
+/// Command line options that take precedence over the config file.
+#[derive(Debug, Args)]
+pub struct Overrides {
+    /// Directory to serve
+    pub root: Option<PathBuf>,
+    /// Address to listen on, can be given more than once
+    #[arg(short, long, value_name = "ADDRESS")]
+    pub bind: Vec<SocketAddr>,
+    /// Certificate chain of the server (PEM)
+    #[arg(long, value_name = "FILE")]
+    pub certificate: Option<PathBuf>,
+    /// Private key of the server (PEM)
+    #[arg(long, value_name = "FILE")]
+    pub private_key: Option<PathBuf>,
+    /// Require client certificates signed by this CA (PEM)
+    #[arg(long, value_name = "FILE")]
+    pub client_ca: Option<PathBuf>,
+    /// Users and their password hashes
+    #[arg(long, value_name = "FILE")]
+    pub credentials: Option<PathBuf>,
+    /// What each user may read and change
+    #[arg(long, value_name = "FILE")]
+    pub permissions: Option<PathBuf>,
+    /// Wrong logins a client gets before it is disconnected
+    #[arg(long, value_name = "N")]
+    pub max_login_attempts: Option<usize>,
+    /// Connections served at the same time
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+    /// Connections served at the same time from one address
+    #[arg(long, value_name = "N")]
+    pub max_connections_per_ip: Option<usize>,
+    /// Seconds a client gets to finish the TLS handshake
+    #[arg(long, value_name = "SECONDS")]
+    pub handshake_timeout: Option<u64>,
+    /// Seconds a client may send nothing, or take nothing, before its connection is closed
+    #[arg(long, value_name = "SECONDS")]
+    pub idle_timeout: Option<u64>,
+    /// Largest frame a client may send, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_frame_size: Option<usize>,
+    /// How much to log
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+    /// How to write the log
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Directory to write the audit log to
+    #[arg(long, value_name = "DIR")]
+    pub audit_dir: Option<PathBuf>,
+    /// Don't keep an audit log
+    #[arg(long, conflicts_with = "audit_dir")]
+    pub no_audit: bool,
+}
+
+impl Config {
+    /// Reads the config file at `path`, if any, and applies `overrides` on top.
+    pub fn load(path: Option<&Path>, overrides: Overrides) -> Result<Self, String> {
+        let mut config = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|error| format!("Can't read {}: {error}", path.display()))?;
+                let config: Config = toml::from_str(&content)
+                    .map_err(|error| format!("Invalid config {}: {error}", path.display()))?;
+                config.relative_to(path.parent().unwrap_or(Path::new("")))
+            }
+            None => Config::default(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(config)
+    }
+    fn relative_to(mut self, directory: &Path) -> Self {
+        for path in [
+            &mut self.certificate,
+            &mut self.private_key,
+            &mut self.root,
+            &mut self.credentials,
+            &mut self.audit.directory,
+        ]
+        .into_iter()
+        .chain(self.client_ca.as_mut())
+        .chain(self.permissions.as_mut())
+        {
+            *path = directory.join(&*path);
+        }
+        self
+    }
+    fn apply(&mut self, overrides: Overrides) {
+        if !overrides.bind.is_empty() {
+            self.bind = overrides.bind;
+        }
+        let paths = [
+            (&mut self.root, overrides.root),
+            (&mut self.certificate, overrides.certificate),
+            (&mut self.private_key, overrides.private_key),
+            (&mut self.credentials, overrides.credentials),
+            (&mut self.audit.directory, overrides.audit_dir),
+        ];
+        for (path, overridden) in paths {
+            if let Some(overridden) = overridden {
+                *path = overridden;
+            }
+        }
+        if overrides.client_ca.is_some() {
+            self.client_ca = overrides.client_ca;
+        }
+        if overrides.permissions.is_some() {
+            self.permissions = overrides.permissions;
+        }
+        if let Some(max_login_attempts) = overrides.max_login_attempts {
+            self.limits.max_login_attempts = max_login_attempts;
+        }
+        if let Some(max_connections) = overrides.max_connections {
+            self.limits.max_connections = max_connections;
+        }
+        if let Some(max_connections_per_ip) = overrides.max_connections_per_ip {
+            self.limits.max_connections_per_ip = max_connections_per_ip;
+        }
+        if let Some(handshake_timeout) = overrides.handshake_timeout {
+            self.limits.handshake_timeout_secs = handshake_timeout;
+        }
+        if let Some(idle_timeout) = overrides.idle_timeout {
+            self.limits.idle_timeout_secs = idle_timeout;
+        }
+        if let Some(max_frame_size) = overrides.max_frame_size {
+            self.limits.max_frame_size = max_frame_size;
+        }
+        if let Some(level) = overrides.log_level {
+            self.logging.level = level;
+        }
+        if let Some(format) = overrides.log_format {
+            self.logging.format = format;
+        }
+        if overrides.no_audit {
+            self.audit.enabled = false;
+        }
+    }
+    /// The permissions file and whether it has to exist, `permissions` next to the
+    /// credentials file is only used if it is there.
+    pub fn permissions_file(&self) -> (PathBuf, bool) {
+        match &self.permissions {
+            Some(permissions) => (permissions.clone(), true),
+            None => (self.credentials.with_file_name("permissions"), false),
+        }
+    }
+    fn validate(&self) -> Result<(), String> {
+        if self.bind.is_empty() {
+            return Err("Invalid config: `bind` needs at least one address".to_string());
+        }
+        let limits = [
+            ("max_login_attempts", self.limits.max_login_attempts as u64),
+            ("max_connections", self.limits.max_connections as u64),
+            (
+                "max_connections_per_ip",
+                self.limits.max_connections_per_ip as u64,
+            ),
+            ("handshake_timeout_secs", self.limits.handshake_timeout_secs),
+            ("idle_timeout_secs", self.limits.idle_timeout_secs),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, value)| *value == 0) {
+            return Err(format!(
+                "Invalid config: `limits.{name}` must be at least 1"
+            ));
+        }
+        if self.limits.max_frame_size < MIN_FRAME_SIZE {
+            return Err(format!(
+                "Invalid config: `limits.max_frame_size` must be at least {MIN_FRAME_SIZE}"
+            ));
+        }
+        if self.audit.keep == Some(0) {
+            return Err("Invalid config: `audit.keep` must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Keeps count of the open connections, in total and per client address.
+pub struct ConnectionLimits {
+    total: Arc<Semaphore>,
+    per_ip: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// One admitted connection, its place is given back when this is dropped.
+pub struct Slot {
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ConnectionLimits {
+    pub fn new(total: usize, per_ip: usize) -> Arc<Self> {
+        Arc::new(ConnectionLimits {
+            total: Arc::new(Semaphore::new(total)),
+            per_ip,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+    /// A slot for a new connection from `ip`, or why there is none.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Slot, &'static str> {
+        let permit = self
+            .total
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| "too many connections")?;
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.per_ip {
+            return Err("too many connections from this address");
+        }
+        *count += 1;
+        Ok(Slot {
+            limits: self.clone(),
+            ip,
+            _permit: permit,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn limits_connections_per_address() {
+        let limits = ConnectionLimits::new(10, 2);
+        let first = limits.admit(ALICE).unwrap();
+        let _second = limits.admit(ALICE).unwrap();
+        assert_eq!(
+            limits.admit(ALICE).err(),
+            Some("too many connections from this address")
+        );
+        let _other = limits.admit(BOB).unwrap();
+        drop(first);
+        let _third = limits.admit(ALICE).unwrap();
+    }
+
+    #[test]
+    fn limits_connections_in_total() {
+        let limits = ConnectionLimits::new(2, 2);
+        let first = limits.admit(ALICE).unwrap();
+        let _second = limits.admit(BOB).unwrap();
+        assert_eq!(limits.admit(BOB).err(), Some("too many connections"));
+        drop(first);
+        let _third = limits.admit(BOB).unwrap();
+    }
+
+    #[test]
+    fn forgets_addresses_without_connections() {
+        let limits = ConnectionLimits::new(10, 1);
+        drop(limits.admit(ALICE).unwrap());
+        assert!(limits.open.lock().unwrap().is_empty());
+        // A refused connection takes no place either
+        let _slot = limits.admit(ALICE).unwrap();
+        assert!(limits.admit(ALICE).is_err());
+        assert_eq!(limits.open.lock().unwrap()[&ALICE], 1);
+        assert_eq!(limits.total.available_permits(), 9);
+    }
+}
@@ -1,136 +1,800 @@
-#![allow(clippy::unused_io_amount)]
-use rustls::ServerConfig;
+use clap::Parser;
+use futures::{future::select_all, SinkExt, StreamExt};
+use rustls::{server::WebPkiClientVerifier, ServerConfig};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{ErrorKind, IsTerminal},
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tar::Builder;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio_rustls::TlsAcceptor;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Take};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_util::codec::Framed;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
+use useful::archive::Unpacker;
+use useful::auth::{add_user, Credentials};
+use useful::compression;
+use useful::idle::IdleTimeout;
+use useful::jail::Jail;
+use useful::permissions::Permissions;
 use useful::prelude::*;
+use useful::protocol::{
+    negotiate, Access, Capability, Compression, ErrorCode, Existing, ProtocolError, Request,
+    Response, ServerCodec, CHUNK_SIZE, PROTOCOL_VERSION,
+};
 use useful::server::*;
-const CERTIFICATE_FILE: &str = "../certificates/server_chain.pem";
-const PK_FILE: &str = "../certificates/server.key";
-const ADDR: &str = "0.0.0.0:13360";
+use useful::tls::{certificate_identity, load_certificates, load_tls};
+
+mod audit;
+mod config;
+mod limits;
+
+use config::{Cli, Command, Config, LogFormat};
+use limits::ConnectionLimits;
+
+const CAPABILITIES: &[Capability] = &[
+    Capability::Zstd,
+    Capability::Gzip,
+    Capability::Resume,
+    Capability::Upload,
+];
 #[tokio::main]
 async fn main() -> UniversalResult<()> {
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref(), cli.overrides) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(2);
+        }
+    };
+    let _audit = match init_logging(&config) {
+        Ok(guard) => guard,
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(2);
+        }
+    };
+    if let Some(Command::Adduser { name }) = cli.command {
+        return add_user_interactively(&config.credentials, name);
+    }
+    let credentials_file = config.credentials.display();
+    let credentials = match Credentials::load(&config.credentials) {
+        Ok(credentials) if !credentials.is_empty() => Arc::new(credentials),
+        // Every client logs in with its certificate, passwords are never asked for
+        Ok(_) if config.client_ca.is_some() => Arc::new(Credentials::default()),
+        Err(error)
+            if error.kind() == std::io::ErrorKind::NotFound && config.client_ca.is_some() =>
+        {
+            Arc::new(Credentials::default())
+        }
+        Ok(_) => {
+            return Err(format!(
+                "{credentials_file} has no users, add one with `ftp-server adduser <name>`"
+            )
+            .into())
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Err(format!(
+                "{credentials_file} does not exist, add a user with `ftp-server adduser <name>`"
+            )
+            .into())
+        }
+        Err(error) => return Err(format!("Can't read {credentials_file}: {error}").into()),
+    };
+    let (permissions_file, required) = config.permissions_file();
+    let permissions = match Permissions::load(&permissions_file) {
+        Ok(permissions) => Arc::new(permissions),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound && !required => {
+            warn!(
+                file = %permissions_file.display(),
+                "no permissions file, every user may read and change everything"
+            );
+            Arc::new(Permissions::unrestricted())
+        }
+        Err(error) => {
+            return Err(format!("Can't read {}: {error}", permissions_file.display()).into())
+        }
+    };
     let socket_config = {
-        let (certificate, privatekey) = load_tls(CERTIFICATE_FILE, PK_FILE)?;
-        ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certificate, privatekey)?
+        let (certificate, privatekey) = load_tls(&config.certificate, &config.private_key)?;
+        let builder = match &config.client_ca {
+            Some(ca_file) => ServerConfig::builder().with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(load_certificates(ca_file)?)).build()?,
+            ),
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        builder.with_single_cert(certificate, privatekey)?
+    };
+
+    let jail = {
+        let root = &config.root;
+        Arc::new(
+            Jail::new(root).map_err(|error| format!("Can't serve {}: {error}", root.display()))?,
+        )
     };
+    info!(root = %jail.root().display(), "serving");
 
     let acceptor = TlsAcceptor::from(Arc::new(socket_config));
-    let socket = TcpListener::bind(ADDR).await?;
-    println!("Listening on address {ADDR} ");
+    let mut sockets = vec![];
+    for address in &config.bind {
+        let socket = TcpListener::bind(address)
+            .await
+            .map_err(|error| format!("Can't listen on {address}: {error}"))?;
+        info!(%address, "listening");
+        sockets.push(socket);
+    }
+    let max_login_attempts = config.limits.max_login_attempts;
+    let handshake_timeout = Duration::from_secs(config.limits.handshake_timeout_secs);
+    let idle_timeout = Duration::from_secs(config.limits.idle_timeout_secs);
+    let max_frame_size = config.limits.max_frame_size;
+    let limits = ConnectionLimits::new(
+        config.limits.max_connections,
+        config.limits.max_connections_per_ip,
+    );
 
+    let mut sessions: u64 = 0;
     loop {
-        let (client, addr) = socket.accept().await?;
-        println!("Accepted {addr}");
+        let accepted = sockets.iter().map(|socket| Box::pin(socket.accept()));
+        let (accepted, _, _) = select_all(accepted).await;
+        let (client, addr) = accepted?;
+        // Turned away before the handshake, so they cost as little as possible
+        let slot = match limits.admit(addr.ip()) {
+            Ok(slot) => slot,
+            Err(reason) => {
+                warn!(peer = %addr, reason, "refused connection");
+                continue;
+            }
+        };
+        sessions += 1;
+        let span = info_span!("connection", peer = %addr, session = sessions, user = field::Empty);
         let acceptor = acceptor.clone();
-        tokio::spawn(async move {
-            let mut client = match acceptor.accept(client).await {
-                Ok(e) => e,
-                Err(err) => {
-                    eprintln!("Handshake failed :( - {err}");
-                    return;
-                }
-            };
-            let thread_result: UniversalResult<()> = async {
-                let mut current_path = std::path::PathBuf::from(".").canonicalize()?;
-
-                let entries = list_directory(&current_path)?.join("\r");
-                let packet = build_packet(entries, '\r');
-                client.write(&packet).await?;
-                loop {
-                    let content_length: usize = {
-                        let mut current_char: [u8; 1] = [0];
-                        let mut buffer = String::new();
-                        while current_char[0] != b'\r' {
-                            client.read_exact(&mut current_char).await?;
-                            buffer.push(current_char[0] as char);
-                        }
-                        buffer.pop();
-                        buffer.parse()?
-                    };
-                    let mut data: Vec<u8> = vec![0; content_length];
-                    client.read_exact(&mut data).await?;
-                    let data = String::from_utf8(data)?;
-                    if &data == "SHUTDOWN" {
-                        client.shutdown().await?;
-                        println!("Successfully closed connection with {addr}");
-                        return Ok(());
+        let jail = jail.clone();
+        let credentials = credentials.clone();
+        let permissions = permissions.clone();
+        tokio::spawn(
+            async move {
+                let _slot = slot;
+                info!("accepted");
+                let client = IdleTimeout::new(client, idle_timeout);
+                let client = match timeout(handshake_timeout, acceptor.accept(client)).await {
+                    Ok(Ok(client)) => client,
+                    Ok(Err(error)) => {
+                        warn!(%error, "TLS handshake failed");
+                        return;
                     }
-                    if let Some(data) = data.strip_prefix("FILE_") {
-                        let content = {
-                            match String::from_utf8(std::fs::read(data)?) {
-                                Ok(e) => e,
-                                Err(_) => {
-                                    let packet = b"fileisbinary\r";
-                                    client.write(packet).await?;
-                                    continue;
-                                }
-                            }
-                        };
-                        let packet = build_packet(content, '\r');
-                        client.write(&packet).await?;
-                    } else if let Some(data) = data.strip_prefix("DIR_") {
-                        if data == ".." {
-                            current_path.push("..");
-                            current_path = current_path.canonicalize().unwrap();
-                        } else {
-                            current_path = PathBuf::from(data);
-                        }
-
-                        let entries = list_directory(&PathBuf::from(&current_path))?.join("\r");
-                        let entries = build_packet(entries, '\r');
-                        client.write(&entries).await?;
-                    } else if let Some(data) = data.strip_prefix("SAVEDIR_") {
-                        let path = Path::new(data);
-                        let mut buffer: Vec<u8> = vec![];
-                        {
-                            let mut builder = Builder::new(&mut buffer);
-                            builder.append_dir_all("", path)?;
-                            builder.finish()?;
-                        }
-                        let mut packet = Vec::from(format!("{}\r", buffer.len()));
-                        packet.extend_from_slice(&buffer);
-
-                        client.write(&packet).await?;
-                    } else if let Some(data) = data.strip_prefix("FILEINFO_") {
-                        let (isfile, entrysize): (u8, u64) = {
-                            let path = std::path::Path::new(data);
-                            let file = std::fs::File::open(path)?;
-                            let metadata = file.metadata().unwrap();
-                            let isfile = if metadata.file_type().is_file() {1} else {0};
-                            println!("File len: {}", metadata.len());
-                            (isfile, metadata.len())
-                        };
-                        {
-                        let packet = build_packet(format!("{isfile}\r{entrysize}"), '\r');
-                        {
-                            let decoded = String::from_utf8(packet.clone())?;
-                            println!("{:?}", decoded.split('\r').collect::<Vec<&str>>());
-                        }
-                        client.write(&packet).await?;
+                    Err(_) => {
+                        warn!("TLS handshake timed out");
+                        return;
+                    }
+                };
+                // Only verified certificates get here, one without a name is no use
+                let identity = match client.get_ref().1.peer_certificates() {
+                    Some([certificate, ..]) => match certificate_identity(certificate) {
+                        Some(identity) => Some(identity),
+                        None => {
+                            warn!("no common name in the client certificate");
+                            return;
                         }
-                    } else {
-                        eprintln!("Error when serving client {addr}: Invalid syntax");
-                        client.shutdown().await?;
-                        return Ok(());
+                    },
+                    _ => None,
+                };
+                let thread_result =
+                    serve(
+                        client,
+                        identity,
+                        jail,
+                        credentials,
+                        permissions,
+                        max_login_attempts,
+                        max_frame_size,
+                    )
+                    .await;
+                if let Err(error) = thread_result {
+                    match io_error_kind(&*error) {
+                        Some(ErrorKind::UnexpectedEof) => warn!("client closed the connection without TLS close_notify (possibly because it got a fatal error)"),
+                        Some(ErrorKind::TimedOut) => info!(%error, "closed the idle connection"),
+                        _ if invalid_frame(&*error) => warn!(%error, "client sent an invalid frame"),
+                        _ => error!(%error, "connection failed"),
                     }
                 }
             }
-            .await;
-            if let Err(error) = thread_result {
-                if error.to_string().contains("peer closed connection without sending TLS close_notify: https://docs.rs/rustls/latest/rustls/manual/_03_howto/index.html#unexpected-eof") {
-                    eprintln!("Client {addr} closed connection without sending TLS close_notify (possibly because the client got a fatal error)");
-                } else {
-                    eprintln!("An error occurred while handling {addr}: {error}");
-                }
+            .instrument(span),
+        );
+    }
+}
+
+/// Runs a session from the `Hello` to the `Shutdown`.
+async fn serve(
+    client: TlsStream<IdleTimeout<TcpStream>>,
+    identity: Option<String>,
+    jail: Arc<Jail>,
+    credentials: Arc<Credentials>,
+    permissions: Arc<Permissions>,
+    max_login_attempts: usize,
+    max_frame_size: usize,
+) -> UniversalResult<()> {
+    let mut client = Framed::new(client, ServerCodec::with_max_frame_size(max_frame_size));
+    let capabilities = match client.next().await {
+        Some(Ok(Request::Hello {
+            version,
+            capabilities,
+        })) => {
+            if version != PROTOCOL_VERSION {
+                warn!(
+                    version,
+                    "unsupported protocol version, rejecting the client"
+                );
+                client
+                    .send(Response::UnsupportedVersion {
+                        supported: PROTOCOL_VERSION,
+                    })
+                    .await?;
+                client.get_mut().shutdown().await?;
+                return Ok(());
+            }
+            let mut ours = CAPABILITIES.to_vec();
+            if identity.is_some() {
+                ours.push(Capability::ClientCertificate);
+            }
+            negotiate(&ours, &capabilities)
+        }
+        Some(Err(error))
+            if !matches!(
+                error,
+                ProtocolError::UnknownTag(_) | ProtocolError::Malformed(_)
+            ) =>
+        {
+            return Err(error.into());
+        }
+        Some(_) => {
+            warn!("expected a HELLO");
+            client.get_mut().shutdown().await?;
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+    info!(?capabilities, "negotiated capabilities");
+    let compression = compression::negotiated(&capabilities);
+    let certified = capabilities.contains(&Capability::ClientCertificate);
+    client
+        .send(Response::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        })
+        .await?;
+    let (username, method) = match identity {
+        Some(identity) if certified => (identity, "certificate"),
+        _ => {
+            let Some(username) = authenticate(&mut client, credentials, max_login_attempts).await?
+            else {
+                client.get_mut().shutdown().await?;
+                return Ok(());
+            };
+            (username, "password")
+        }
+    };
+    Span::current().record("user", username.as_str());
+    info!(method, "logged in");
+    let jail = jail.for_user(permissions.for_user(&username));
+    let mut current_path = jail.root().to_path_buf();
+
+    let listing = list_directory(&current_path, &jail)?;
+    client.send(listing).await?;
+    while let Some(request) = client.next().await {
+        let request = match request {
+            Ok(request) => request,
+            Err(error @ (ProtocolError::UnknownTag(_) | ProtocolError::Malformed(_))) => {
+                warn!(%error, "invalid request");
+                client
+                    .send(Response::error(ErrorCode::InvalidRequest, error))
+                    .await?;
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let (op, path) = (request.name(), request.path().map(str::to_string));
+        debug!(op, path, "request");
+        match request {
+            Request::Hello { .. } => {
+                warn!("duplicate HELLO");
+                client.get_mut().shutdown().await?;
+                return Ok(());
+            }
+            Request::Shutdown => {
+                client.get_mut().shutdown().await?;
+                info!("closed the connection");
+                return Ok(());
+            }
+            _ => (),
+        }
+        let audited = matches!(
+            request,
+            Request::Dir(_)
+                | Request::FileInfo(_)
+                | Request::File(_)
+                | Request::ReadRange { .. }
+                | Request::SaveDir(_)
+        );
+        // What the request was about inside the served tree, resolved before a DIR
+        // moves away from it
+        let target = match &path {
+            Some(path) if audited => jail
+                .resolve(&current_path, path, Access::Browse)
+                .ok()
+                .map(|real| jail.to_virtual(&real)),
+            _ => None,
+        };
+        let (reply, _) = {
+            let started = Instant::now();
+            let handled = handle(&mut client, request, &jail, &mut current_path, compression).await;
+            if audited {
+                let (bytes, result) = match &handled {
+                    Ok((Some(Response::Error { code, .. }), sent)) => (*sent, code.name()),
+                    Ok((_, sent)) => (*sent, "ok"),
+                    Err(_) => (0, "disconnected"),
+                };
+                let requested = path.as_deref();
+                audit::record(
+                    op,
+                    target.as_deref(),
+                    requested,
+                    bytes,
+                    result,
+                    started.elapsed(),
+                );
+            }
+            handled?
+        };
+        match &reply {
+            Some(Response::Error { code, message }) => {
+                warn!(op, path, %code, error = message, "refused")
+            }
+            _ => info!(op, path, "done"),
+        }
+        if let Some(reply) = reply {
+            client.send(reply).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Carries out a request of a logged in session, returning what to answer with
+/// (nothing after a complete download) and how much content was sent.
+async fn handle(
+    client: &mut Connection,
+    request: Request,
+    jail: &Jail,
+    current_path: &mut PathBuf,
+    compression: Compression,
+) -> UniversalResult<(Option<Response>, u64)> {
+    let mut sent = 0;
+    let reply = match request {
+        Request::File(path) => match open_file(jail, current_path, &path).await {
+            Ok(file) => {
+                let size = file.metadata().await.ok().map(|metadata| metadata.len());
+                let compression = compression::for_file(Path::new(&path), compression);
+                let (transferred, failed) =
+                    send_transfer(client, file, size, Sha256::new(), compression).await?;
+                sent = transferred;
+                failed
+            }
+            Err(error) => Some(error.into()),
+        },
+        Request::ReadRange {
+            path,
+            offset,
+            length,
+        } => match open_range(jail, current_path, &path, offset, length).await {
+            Ok((range, size, hasher)) => {
+                let compression = compression::for_file(Path::new(&path), compression);
+                let (transferred, failed) =
+                    send_transfer(client, range, Some(size), hasher, compression).await?;
+                sent = transferred;
+                failed
+            }
+            Err(error) => Some(error.into()),
+        },
+        Request::SaveDir(path) => match jail.resolve(current_path, &path, Access::Read) {
+            Ok(directory) if directory.is_dir() => {
+                let archive = stream_archive(directory, jail.clone());
+                let (transferred, failed) =
+                    send_transfer(client, archive, None, Sha256::new(), compression).await?;
+                sent = transferred;
+                failed
+            }
+            Ok(_) => Some(Response::error(
+                ErrorCode::NotADirectory,
+                format!("{path} is not a directory"),
+            )),
+            Err(error) => Some(error.into()),
+        },
+        Request::Put {
+            path,
+            overwrite,
+            compression,
+        } => match create_part(jail, current_path, &path, overwrite) {
+            Ok((file, part, target)) => {
+                client.send(Response::Ready).await?;
+                Some(receive_upload(client, file, &part, &target, compression).await?)
+            }
+            Err(error) => Some(error.into()),
+        },
+        Request::PutDir {
+            path,
+            existing,
+            compression,
+        } => match create_target_dir(jail, current_path, &path) {
+            Ok((target, created)) => {
+                client.send(Response::Ready).await?;
+                let received =
+                    receive_archive(client, jail, &target, created, existing, compression);
+                Some(received.await?)
+            }
+            Err(error) => Some(error.into()),
+        },
+        request => Some(respond(request, jail, current_path).unwrap_or_else(Response::from)),
+    };
+    Ok((reply, sent))
+}
+
+/// The kind of IO error behind `error`, `UnexpectedEof` is rustls telling that the
+/// client went away without saying goodbye and `TimedOut` that it was idle too long.
+fn io_error_kind(error: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    let error = match error.downcast_ref::<ProtocolError>() {
+        Some(ProtocolError::Io(error)) => Some(error),
+        _ => error.downcast_ref::<std::io::Error>(),
+    };
+    error.map(|error| error.kind())
+}
+
+/// Whether the client broke the framing, after which nothing it sends can be read.
+fn invalid_frame(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::InvalidLength(_) | ProtocolError::FrameTooLarge { .. })
+    )
+}
+
+/// Waits for a `Login` with valid credentials and returns the username, or nothing
+/// once the client gave up or had too many tries.
+async fn authenticate(
+    client: &mut Connection,
+    credentials: Arc<Credentials>,
+    max_attempts: usize,
+) -> UniversalResult<Option<String>> {
+    for attempt in 1..=max_attempts {
+        let (username, password) = match client.next().await {
+            Some(Ok(Request::Login { username, password })) => (username, password),
+            Some(Ok(_)) => {
+                warn!("expected a LOGIN");
+                client
+                    .send(Response::error(
+                        ErrorCode::AuthenticationFailed,
+                        "Log in first",
+                    ))
+                    .await?;
+                return Ok(None);
+            }
+            Some(Err(error)) => return Err(error.into()),
+            None => return Ok(None),
+        };
+        let verified = {
+            let credentials = credentials.clone();
+            let username = username.clone();
+            // Argon2 is slow on purpose, keep it off the async workers
+            tokio::task::spawn_blocking(move || credentials.verify(&username, &password)).await?
+        };
+        if verified {
+            return Ok(Some(username));
+        }
+        warn!(username, "failed to log in");
+        let message = if attempt == max_attempts {
+            "Wrong username or password, giving up"
+        } else {
+            "Wrong username or password"
+        };
+        client
+            .send(Response::error(ErrorCode::AuthenticationFailed, message))
+            .await?;
+    }
+    Ok(None)
+}
+
+/// Events go to stdout, with the connection they belong to, audit records to
+/// their own files. The returned guard keeps the audit log going.
+fn init_logging(config: &Config) -> Result<Option<WorkerGuard>, String> {
+    let level = LevelFilter::from_level(config.logging.level.into());
+    let log = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+    let log = match config.logging.format {
+        LogFormat::Text => log.boxed(),
+        LogFormat::Json => log.json().boxed(),
+    };
+    let mut layers = vec![log.with_filter(level).with_filter(audit::exclude()).boxed()];
+    let mut guard = None;
+    if config.audit.enabled {
+        let (layer, audit_guard) = audit::layer(&config.audit)?;
+        layers.push(layer);
+        guard = Some(audit_guard);
+    }
+    tracing_subscriber::registry().with(layers).init();
+    Ok(guard)
+}
+
+/// `ftp-server adduser <name>`, asks for the password on stdin.
+fn add_user_interactively(credentials: &Path, username: String) -> UniversalResult<()> {
+    print!("Password for {username}: ");
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("The password can't be empty".into());
+    }
+    add_user(credentials, &username, password)?;
+    println!("Added {username} to {}", credentials.display());
+    Ok(())
+}
+
+/// Opens a new `.<name>.<random>.part` next to the target of an upload into `path`,
+/// also returning both paths.
+fn create_part(
+    jail: &Jail,
+    current_path: &Path,
+    path: &str,
+    overwrite: bool,
+) -> std::io::Result<(std::fs::File, PathBuf, PathBuf)> {
+    let target = jail.resolve_entry(current_path, path)?;
+    if target.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::IsADirectory,
+            format!("{path} is a directory"),
+        ));
+    }
+    if !overwrite && target.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{path} already exists"),
+        ));
+    }
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    // A fresh name every time, so whatever is already there (another upload of the
+    // same file, a user's own `.part` file or a symlink) is left alone
+    loop {
+        let random = RandomState::new().build_hasher().finish();
+        let part = target.with_file_name(format!(".{name}.{random:016x}.part"));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part)
+        {
+            Ok(file) => return Ok((file, part, target)),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Receives an upload into `part` and moves it to `target` once it is complete,
+/// returning what to finish the upload with. Nothing is left behind on failure.
+async fn receive_upload(
+    client: &mut Connection,
+    file: std::fs::File,
+    part: &Path,
+    target: &Path,
+    compression: Compression,
+) -> UniversalResult<Response> {
+    let response = match receive_transfer(client, file, compression).await {
+        Ok(Ok(_)) => match std::fs::rename(part, target) {
+            Ok(()) => return Ok(Response::Done),
+            Err(error) => Response::from(error),
+        },
+        Ok(Err(response)) => response,
+        Err(error) => {
+            let _ = std::fs::remove_file(part);
+            return Err(error);
+        }
+    };
+    let _ = std::fs::remove_file(part);
+    Ok(response)
+}
+
+/// Makes sure there is a directory to unpack an upload into, returning whether it
+/// had to be created. A symlink is not followed.
+fn create_target_dir(
+    jail: &Jail,
+    current_path: &Path,
+    path: &str,
+) -> std::io::Result<(PathBuf, bool)> {
+    let target = jail.resolve_entry(current_path, path)?;
+    match std::fs::symlink_metadata(&target) {
+        Ok(metadata) if metadata.is_dir() => Ok((target, false)),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotADirectory,
+            format!("{path} is not a directory"),
+        )),
+        Err(_) => std::fs::create_dir(&target).map(|()| (target, true)),
+    }
+}
+
+/// Unpacks an uploaded tar into `target` while it arrives, returning what to finish
+/// the upload with. Entries the user may not change are skipped, a directory we
+/// `created` is removed again on failure.
+async fn receive_archive(
+    client: &mut Connection,
+    jail: &Jail,
+    target: &Path,
+    created: bool,
+    existing: Existing,
+    compression: Compression,
+) -> UniversalResult<Response> {
+    let writable = {
+        let jail = jail.clone();
+        move |real: &Path| jail.access(real) == Some(Access::Write)
+    };
+    let mut unpacker = Unpacker::filtered(target.to_path_buf(), existing, writable);
+    let received = receive_transfer(client, &mut unpacker, compression).await;
+    let unpacked = unpacker.finish();
+    let response = match (received, unpacked) {
+        (Ok(Ok(_)), Ok(())) => return Ok(Response::Done),
+        (Ok(Ok(_)), Err(error)) => Response::from(error),
+        (Ok(Err(response)), _) => response,
+        (Err(error), _) => {
+            if created {
+                let _ = std::fs::remove_dir_all(target);
             }
-        });
+            return Err(error);
+        }
+    };
+    if created {
+        let _ = std::fs::remove_dir_all(target);
+    }
+    Ok(response)
+}
+
+async fn open_file(
+    jail: &Jail,
+    current_path: &Path,
+    path: &str,
+) -> std::io::Result<tokio::fs::File> {
+    tokio::fs::File::open(jail.resolve(current_path, path, Access::Read)?).await
+}
+
+/// Also returns the hash of everything before `offset`, which the checksum of the
+/// transfer has to cover.
+async fn open_range(
+    jail: &Jail,
+    current_path: &Path,
+    path: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> std::io::Result<(Take<tokio::fs::File>, u64, Sha256)> {
+    let mut file = open_file(jail, current_path, path).await?;
+    let file_length = file.metadata().await?.len();
+    if offset > file_length {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("offset {offset} is past the end of the file ({file_length} bytes)"),
+        ));
+    }
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut prefix = (&mut file).take(offset);
+    loop {
+        match prefix.read(&mut chunk).await? {
+            0 => break,
+            read => hasher.update(&chunk[..read]),
+        }
+    }
+    let remaining = file_length - offset;
+    let size = length.map_or(remaining, |length| length.min(remaining));
+    Ok((file.take(size), size, hasher))
+}
+
+/// Resolves the entry a rename or delete works on, which can't be the directory the
+/// session is in or one above it.
+fn entry_path(jail: &Jail, current_path: &Path, path: &str) -> std::io::Result<PathBuf> {
+    let resolved = jail.resolve_entry(current_path, path)?;
+    if current_path.starts_with(&resolved) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{path} is being browsed"),
+        ));
     }
+    Ok(resolved)
+}
+
+fn respond(request: Request, jail: &Jail, current_path: &mut PathBuf) -> std::io::Result<Response> {
+    Ok(match request {
+        Request::Dir(path) => {
+            let new_path = jail.resolve(current_path, &path, Access::Browse)?;
+
+            let listing = list_directory(&new_path, jail)?;
+            *current_path = new_path;
+            listing
+        }
+        Request::FileInfo(path) => {
+            let resolved = jail.resolve(current_path, &path, Access::Browse)?;
+            let metadata = std::fs::File::open(&resolved)?.metadata()?;
+            Response::FileInfo {
+                is_file: metadata.file_type().is_file(),
+                size: metadata.len(),
+                access: jail.access(&resolved).unwrap_or(Access::Browse),
+            }
+        }
+        Request::Mkdir(path) => {
+            std::fs::create_dir(jail.resolve_entry(current_path, &path)?)?;
+            Response::Done
+        }
+        Request::Rename { from, to } => {
+            let resolved = entry_path(jail, current_path, &from)?;
+            // Entries below it that are off limits would be reachable by the new name
+            let metadata = std::fs::symlink_metadata(&resolved)?;
+            if metadata.is_dir() && !jail.writable_tree(&resolved)? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{from} contains entries you may not change"),
+                ));
+            }
+            let target = jail.resolve_entry(current_path, &to)?;
+            // rename would silently replace a file
+            if target.symlink_metadata().is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{to} already exists"),
+                ));
+            }
+            std::fs::rename(resolved, target)?;
+            Response::Done
+        }
+        Request::Delete { path, recursive } => {
+            let resolved = entry_path(jail, current_path, &path)?;
+            let metadata = std::fs::symlink_metadata(&resolved)?;
+            if !metadata.is_dir() {
+                std::fs::remove_file(resolved)?;
+            } else if recursive {
+                // Hidden entries would go along without the user knowing
+                if !jail.writable_tree(&resolved)? {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        format!("{path} contains entries you may not change"),
+                    ));
+                }
+                std::fs::remove_dir_all(resolved)?;
+            } else {
+                std::fs::remove_dir(resolved)?;
+            }
+            Response::Done
+        }
+        Request::Login { .. } => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "already logged in",
+            ))
+        }
+        Request::Data(_) | Request::TransferEnd { .. } | Request::Abort => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "there is no upload in progress",
+            ))
+        }
+        request @ (Request::Hello { .. }
+        | Request::Shutdown
+        | Request::File(_)
+        | Request::ReadRange { .. }
+        | Request::SaveDir(_)
+        | Request::Put { .. }
+        | Request::PutDir { .. }) => {
+            unreachable!("{request:?} is handled by the connection loop")
+        }
+    })
 }
@@ -0,0 +1,460 @@
+use std::{
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread::JoinHandle,
+};
+
+use tar::EntryType;
+
+use crate::protocol::{Existing, CHUNK_SIZE};
+
+/// A `Write` that unpacks the tar stream written into it on a separate thread,
+/// so a directory can be unpacked while it is still being transferred.
+pub struct Unpacker {
+    sender: Option<SyncSender<std::io::Result<Vec<u8>>>>,
+    unpacking: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl Unpacker {
+    pub fn new(destination: PathBuf, existing: Existing) -> Self {
+        Self::filtered(destination, existing, |_| true)
+    }
+    /// Like `new`, but entries `keep` says no to are left out. It is given the path
+    /// an entry ends up at on disk.
+    pub fn filtered(
+        destination: PathBuf,
+        existing: Existing,
+        keep: impl Fn(&Path) -> bool + Send + 'static,
+    ) -> Self {
+        let (sender, receiver) = sync_channel(16);
+        let unpacking = std::thread::spawn(move || {
+            let reader = ChannelReader {
+                receiver,
+                chunk: vec![],
+                position: 0,
+            };
+            unpack(reader, &destination, existing, keep)
+        });
+        Unpacker {
+            sender: Some(sender),
+            unpacking: Some(unpacking),
+        }
+    }
+    /// Waits until everything written so far is unpacked.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.sender.take();
+        self.join()
+    }
+    fn join(&mut self) -> std::io::Result<()> {
+        match self.unpacking.take() {
+            Some(unpacking) => unpacking
+                .join()
+                .map_err(|_| std::io::Error::other("unpacking thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for Unpacker {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let sent = match &self.sender {
+            Some(sender) => sender.send(Ok(buf.to_vec())).is_ok(),
+            None => false,
+        };
+        if !sent {
+            // The unpacking thread stopped early, its error says why
+            self.sender.take();
+            self.join()?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the archive ended before the transfer did",
+            ));
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Tars `directory` on a separate thread while the returned reader is read, so the
+/// archive never has to fit in memory. A failure half way comes out of the reader.
+pub fn pack(directory: PathBuf) -> impl Read + Send {
+    let (sender, receiver) = sync_channel(4);
+    std::thread::spawn(move || {
+        let result = write_archive(
+            |chunk| sender.send(Ok(chunk)),
+            |builder| builder.append_dir_all("", &directory),
+        );
+        if let Err(error) = result {
+            let _ = sender.send(Err(error));
+        }
+    });
+    ChannelReader {
+        receiver,
+        chunk: vec![],
+        position: 0,
+    }
+}
+
+/// Unpacks the archive in `reader` into `destination`, skipping entries `keep`
+/// rejects and links to something it rejects. `keep` is given real paths, with the
+/// symlinks already on disk followed (those unpacked earlier too). Entries that
+/// would end up outside of `destination`, directly or through a link, fail the
+/// whole archive.
+pub fn unpack<R: Read>(
+    reader: R,
+    destination: &Path,
+    existing: Existing,
+    keep: impl Fn(&Path) -> bool,
+) -> std::io::Result<()> {
+    let destination = destination.canonicalize()?;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        // Where a link leads, relative to `destination` like `path`
+        let link = match entry.header().entry_type() {
+            EntryType::Symlink => entry
+                .link_name()?
+                .map(|link| path.parent().unwrap_or(Path::new("")).join(link)),
+            EntryType::Link => entry.link_name()?.map(|link| link.into_owned()),
+            _ => None,
+        };
+        let escaping = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} escapes the target directory", path.display()),
+            )
+        };
+        if escapes(&path) || link.as_deref().is_some_and(escapes) {
+            return Err(escaping());
+        }
+        let real = on_disk(&destination, &path)?.ok_or_else(escaping)?;
+        let real_link = match &link {
+            Some(link) => Some(on_disk(&destination, link)?.ok_or_else(escaping)?),
+            None => None,
+        };
+        // A link would give something that is off limits a second name
+        if !keep(&real) || real_link.is_some_and(|link| !keep(&link)) {
+            continue;
+        }
+        let exists = destination.join(&path).symlink_metadata().is_ok();
+        if exists && existing == Existing::Skip && !entry.header().entry_type().is_dir() {
+            continue;
+        }
+        entry.unpack_in(&destination)?;
+    }
+    Ok(())
+}
+
+/// Where `path`, relative to `destination`, is on disk: the part that exists with
+/// its symlinks followed and the rest as it is. The last component is not followed,
+/// tar replaces rather than writes through it. `None` if it is not below
+/// `destination`, which has to be canonical.
+fn on_disk(destination: &Path, path: &Path) -> std::io::Result<Option<PathBuf>> {
+    let path = normalize(path);
+    let Some(name) = path.file_name() else {
+        return Ok(Some(destination.to_path_buf()));
+    };
+    let mut existing = destination.join(&path);
+    existing.pop();
+    let mut missing = vec![];
+    let real = loop {
+        match existing.canonicalize() {
+            Ok(real) => break real,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                missing.extend(existing.file_name().map(ToOwned::to_owned));
+                if !existing.pop() {
+                    return Err(error);
+                }
+            }
+            Err(error) => return Err(error),
+        }
+    };
+    if !real.starts_with(destination) {
+        return Ok(None);
+    }
+    Ok(Some(
+        missing
+            .iter()
+            .rev()
+            .fold(real, |real, part| real.join(part))
+            .join(name),
+    ))
+}
+
+/// `path` without `.` and `..`, for paths that don't escape.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+/// Whether `path`, taken relative to some directory, points outside of it.
+fn escapes(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Builds a tar with `build`, handing it to `send` in CHUNK_SIZE pieces as it is
+/// written. `send` failing means nobody wants the rest of the archive.
+pub fn write_archive<E>(
+    send: impl FnMut(Vec<u8>) -> Result<(), E>,
+    build: impl FnOnce(&mut tar::Builder<&mut dyn Write>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut writer = ChunkWriter {
+        send,
+        buffer: Vec::with_capacity(CHUNK_SIZE),
+    };
+    let mut builder = tar::Builder::new(&mut writer as &mut dyn Write);
+    build(&mut builder)?;
+    builder.finish()?;
+    drop(builder);
+    writer.flush()
+}
+
+struct ChunkWriter<F> {
+    send: F,
+    buffer: Vec<u8>,
+}
+
+impl<E, F: FnMut(Vec<u8>) -> Result<(), E>> ChunkWriter<F> {
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        let chunk = std::mem::take(&mut self.buffer);
+        (self.send)(chunk).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the transfer was abandoned")
+        })
+    }
+}
+
+impl<E, F: FnMut(Vec<u8>) -> Result<(), E>> Write for ChunkWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+struct ChannelReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                // The sending side is done
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jail::Jail, permissions::Permissions, protocol::Access};
+    use std::fs;
+
+    enum Item<'a> {
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+    }
+
+    fn archive(items: &[Item]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for item in items {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            let (path, content, link) = match item {
+                Item::File(path, content) => {
+                    header.set_entry_type(EntryType::Regular);
+                    (path, content.as_bytes(), None)
+                }
+                Item::Symlink(path, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    (path, &[][..], Some(target))
+                }
+                Item::HardLink(path, target) => {
+                    header.set_entry_type(EntryType::Link);
+                    (path, &[][..], Some(target))
+                }
+            };
+            header.set_size(content.len() as u64);
+            // Straight into the header, `append_data` would refuse the bad paths
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(target) = link {
+                header.as_old_mut().linkname[..target.len()].copy_from_slice(target.as_bytes());
+            }
+            header.set_cksum();
+            builder.append(&header, content).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// An empty `destination` with a `secret` next to it.
+    fn destination() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("destination")).unwrap();
+        fs::write(dir.path().join("secret"), "outside").unwrap();
+        let destination = dir.path().join("destination");
+        (dir, destination)
+    }
+
+    fn escaping(result: std::io::Result<()>) -> bool {
+        matches!(result, Err(error) if error.kind() == std::io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn rejects_entries_leading_outside() {
+        let (dir, destination) = destination();
+        let unpack_all = |items: &[Item]| {
+            unpack(
+                &archive(items)[..],
+                &destination,
+                Existing::Overwrite,
+                |_| true,
+            )
+        };
+        assert!(escaping(unpack_all(&[Item::File("../x", "x")])));
+        assert!(escaping(unpack_all(&[Item::File("inner/../../x", "x")])));
+        assert!(escaping(unpack_all(&[Item::File("/tmp/x", "x")])));
+        assert!(escaping(unpack_all(&[Item::Symlink("s", "../secret")])));
+        assert!(escaping(unpack_all(&[Item::Symlink("s", "/etc/passwd")])));
+        assert!(escaping(unpack_all(&[Item::HardLink("h", "../secret")])));
+        assert!(!dir.path().join("x").exists());
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 0);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("secret")).unwrap(),
+            "outside"
+        );
+    }
+
+    #[test]
+    fn skips_or_overwrites_existing_files() {
+        let (_dir, destination) = destination();
+        fs::write(destination.join("old"), "old").unwrap();
+        let items = [Item::File("old", "replaced"), Item::File("new", "new")];
+        unpack(&archive(&items)[..], &destination, Existing::Skip, |_| true).unwrap();
+        assert_eq!(fs::read_to_string(destination.join("old")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(destination.join("new")).unwrap(), "new");
+        unpack(
+            &archive(&items)[..],
+            &destination,
+            Existing::Overwrite,
+            |_| true,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(destination.join("old")).unwrap(),
+            "replaced"
+        );
+    }
+
+    /// A jail with `drafts1/plan.txt` that alice may not change.
+    fn served() -> (tempfile::TempDir, Jail) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("served/drafts1")).unwrap();
+        fs::write(dir.path().join("served/drafts1/plan.txt"), "plan").unwrap();
+        fs::write(dir.path().join("permissions"), "alice rw / /drafts*\n").unwrap();
+        let permissions = Permissions::load(dir.path().join("permissions")).unwrap();
+        let jail = Jail::new(dir.path().join("served")).unwrap();
+        let alice = jail.for_user(permissions.for_user("alice"));
+        (dir, alice)
+    }
+
+    fn unpack_as(jail: &Jail, items: &[Item]) -> std::io::Result<()> {
+        let writable = |real: &Path| jail.access(real) == Some(Access::Write);
+        unpack(
+            &archive(items)[..],
+            jail.root(),
+            Existing::Overwrite,
+            writable,
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_write_through_symlinks_into_entries_that_are_off_limits() {
+        let (_dir, jail) = served();
+        let root = jail.root();
+        unpack_as(
+            &jail,
+            &[
+                Item::Symlink("l", "."),
+                Item::Symlink("d", "l/drafts1"),
+                Item::File("d/plan.txt", "overwritten"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("drafts1/plan.txt")).unwrap(),
+            "plan"
+        );
+        assert!(root.join("d").symlink_metadata().unwrap().is_dir());
+        // The same through a symlink that was there before
+        std::os::unix::fs::symlink("drafts1", root.join("existing")).unwrap();
+        unpack_as(&jail, &[Item::File("existing/plan.txt", "overwritten")]).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("drafts1/plan.txt")).unwrap(),
+            "plan"
+        );
+    }
+
+    #[test]
+    fn leaves_out_links_to_entries_that_are_off_limits() {
+        let (_dir, jail) = served();
+        let root = jail.root();
+        unpack_as(
+            &jail,
+            &[
+                Item::File("notes", "notes"),
+                Item::HardLink("copy", "drafts1/plan.txt"),
+                Item::HardLink("notes2", "notes"),
+            ],
+        )
+        .unwrap();
+        assert!(root.join("copy").symlink_metadata().is_err());
+        assert_eq!(fs::read_to_string(root.join("notes2")).unwrap(), "notes");
+    }
+}
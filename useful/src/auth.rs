@@ -0,0 +1,103 @@
+use std::{collections::HashMap, io::Write, path::Path, sync::OnceLock};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+
+/*
+The credentials file has one `username:hash` line per user, the hash being an
+Argon2 PHC string which carries its own salt and parameters. Empty lines and
+lines starting with `#` are skipped.
+*/
+/// The users allowed to log in, as read from the credentials file.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line
+                .split_once(':')
+                .filter(|(_, hash)| PasswordHash::new(hash).is_ok())
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "{}:{} is not a valid `user:hash` line",
+                            path.display(),
+                            number + 1
+                        ),
+                    )
+                })?;
+            users.insert(username.to_string(), hash.to_string());
+        }
+        // Hashed now rather than on the first unknown user, who would stand out
+        dummy_hash();
+        Ok(Credentials { users })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+    /// Whether `password` belongs to `username`. Slow on purpose, unknown users
+    /// are checked against a dummy hash so they take as long as wrong passwords.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let (hash, known) = match self.users.get(username) {
+            Some(hash) => (hash.as_str(), true),
+            None => (dummy_hash(), false),
+        };
+        let verified = PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+        known && verified
+    }
+}
+
+/// What unknown users are checked against, made once since hashing costs as much
+/// as verifying.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password(""))
+}
+
+/// A salted Argon2 hash of `password`, as stored in the credentials file.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("default Argon2 parameters accept any password")
+        .to_string()
+}
+
+/// Adds `username` to the credentials file at `path`, creating it if needed.
+pub fn add_user(path: impl AsRef<Path>, username: &str, password: &str) -> std::io::Result<()> {
+    if username.is_empty() || username.contains([':', '\r', '\n']) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "usernames can't be empty or contain `:` or line breaks",
+        ));
+    }
+    let path = path.as_ref();
+    if path.exists() && Credentials::load(path)?.users.contains_key(username) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{username} already exists"),
+        ));
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{username}:{}", hash_password(password))
+}
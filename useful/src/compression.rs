@@ -0,0 +1,129 @@
+use std::io::Write;
+
+use flate2::{write::GzDecoder, write::GzEncoder};
+
+use crate::protocol::{Capability, Compression};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/*
+Extensions of files that are compressed already, running them through zstd or
+gzip again only costs CPU time.
+*/
+const ALREADY_COMPRESSED: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "deb", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "pptx", "rar",
+    "rpm", "tbz2", "tgz", "txz", "webm", "webp", "whl", "woff", "woff2", "xlsx", "xz", "zip",
+    "zst",
+];
+
+/// The compression both sides agreed on in their `Hello`, the first one wins.
+pub fn negotiated(capabilities: &[Capability]) -> Compression {
+    capabilities
+        .iter()
+        .find_map(|capability| match capability {
+            Capability::Zstd => Some(Compression::Zstd),
+            Capability::Gzip => Some(Compression::Gzip),
+            _ => None,
+        })
+        .unwrap_or(Compression::None)
+}
+
+/// Picks what a file transfer should use, skipping content that won't shrink.
+pub fn for_file(path: &std::path::Path, session: Compression) -> Compression {
+    let already_compressed = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| ALREADY_COMPRESSED.contains(&extension.to_lowercase().as_str()));
+    if already_compressed {
+        Compression::None
+    } else {
+        session
+    }
+}
+
+/// Compresses a transfer chunk by chunk, handing back whatever output is ready.
+pub enum Compressor {
+    None,
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Compressor::None,
+            Compression::Zstd => {
+                Compressor::Zstd(zstd::stream::write::Encoder::new(vec![], ZSTD_LEVEL)?)
+            }
+            Compression::Gzip => {
+                Compressor::Gzip(GzEncoder::new(vec![], flate2::Compression::default()))
+            }
+        })
+    }
+    pub fn compress(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        Ok(match self {
+            Compressor::None => chunk.to_vec(),
+            Compressor::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+            Compressor::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+    /// The trailing bytes that end the compressed stream.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compressor::None => Ok(vec![]),
+            Compressor::Zstd(encoder) => encoder.finish(),
+            Compressor::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Undoes a `Compressor`, writing the original bytes into `W`.
+pub enum Decompressor<W: Write> {
+    None(W),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+    Gzip(GzDecoder<W>),
+}
+
+impl<W: Write> Decompressor<W> {
+    pub fn new(compression: Compression, writer: W) -> std::io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Decompressor::None(writer),
+            Compression::Zstd => Decompressor::Zstd(zstd::stream::write::Decoder::new(writer)?),
+            Compression::Gzip => Decompressor::Gzip(GzDecoder::new(writer)),
+        })
+    }
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            Decompressor::None(writer) => Ok(writer),
+            Decompressor::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+            Decompressor::Gzip(decoder) => decoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Decompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Decompressor::None(writer) => writer.write(buf),
+            Decompressor::Zstd(decoder) => decoder.write(buf),
+            Decompressor::Gzip(decoder) => decoder.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Decompressor::None(writer) => writer.flush(),
+            Decompressor::Zstd(decoder) => decoder.flush(),
+            Decompressor::Gzip(decoder) => decoder.flush(),
+        }
+    }
+}
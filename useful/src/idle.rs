@@ -0,0 +1,190 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/*
+The clock runs while the other side keeps the connection waiting: from the moment
+a read has nothing to return until bytes come in, and from the moment a write or
+flush can't get rid of anything until it can. A client that is being sent a long
+download is not idle as long as it takes what is sent, one that goes quiet halfway
+through an upload, between two requests or stops reading a download is. Reads and
+writes have a clock each.
+*/
+/// A stream whose reads and writes fail with `TimedOut` once the other side has
+/// sent nothing, or taken nothing of what is being sent to it, for `timeout`.
+pub struct IdleTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(inner: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            inner,
+            timeout,
+            read_deadline: None,
+            write_deadline: None,
+        }
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Finishes `poll`, the outcome of the inner stream, as is when it is ready and
+/// with `TimedOut` once it has been pending for `timeout` in a row.
+fn timed<T>(
+    poll: Poll<io::Result<T>>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+    what: &str,
+) -> Poll<io::Result<T>> {
+    if poll.is_ready() {
+        *deadline = None;
+        return poll;
+    }
+    let sleep = deadline.get_or_insert_with(|| Box::pin(sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *deadline = None;
+            Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{what} for {}s", timeout.as_secs()),
+            )))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let received = "nothing was received";
+        timed(poll, &mut this.read_deadline, this.timeout, cx, received)
+    }
+}
+
+const NOT_SENT: &str = "nothing could be sent";
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        timed(poll, &mut this.write_deadline, this.timeout, cx, NOT_SENT)
+    }
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        timed(poll, &mut this.write_deadline, this.timeout, cx, NOT_SENT)
+    }
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        timed(poll, &mut this.write_deadline, this.timeout, cx, NOT_SENT)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        timed(poll, &mut this.write_deadline, this.timeout, cx, NOT_SENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_a_peer_that_sends_nothing() {
+        let (near, mut far) = duplex(64);
+        let mut stream = IdleTimeout::new(near, TIMEOUT);
+        let mut buf = [0; 8];
+        far.write_all(b"hello").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 5);
+
+        let started = tokio::time::Instant::now();
+        let error = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_as_long_as_the_peer_keeps_sending() {
+        let (near, mut far) = duplex(64);
+        let mut stream = IdleTimeout::new(near, TIMEOUT);
+        tokio::spawn(async move {
+            for _ in 0..5 {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                far.write_all(b"x").await.unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        // Ends with the peer, long after a single timeout has passed
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"xxxxx");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_a_peer_that_takes_nothing() {
+        let (near, mut far) = duplex(64);
+        let mut stream = IdleTimeout::new(near, TIMEOUT);
+        let reader = tokio::spawn(async move {
+            let mut buf = [0; 64];
+            far.read_exact(&mut buf).await.unwrap();
+            // Keeps the connection open without reading any further
+            tokio::time::sleep(TIMEOUT * 10).await;
+        });
+        let started = tokio::time::Instant::now();
+        let error = stream.write_all(&[0; 1024]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), TIMEOUT);
+        reader.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_as_long_as_the_peer_keeps_taking() {
+        let (near, mut far) = duplex(64);
+        let mut stream = IdleTimeout::new(near, TIMEOUT);
+        let reader = tokio::spawn(async move {
+            let mut buf = [0; 64];
+            let mut received = 0;
+            while received < 512 {
+                tokio::time::sleep(TIMEOUT / 2).await;
+                received += far.read(&mut buf).await.unwrap();
+            }
+        });
+        stream.write_all(&[0; 512]).await.unwrap();
+        stream.flush().await.unwrap();
+        reader.await.unwrap();
+    }
+}
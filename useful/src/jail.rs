@@ -0,0 +1,313 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{permissions::UserPermissions, protocol::Access};
+
+/*
+Everything a client names is resolved against the directory the session is in,
+or against the served root if it starts with `/`, and has to stay below the
+root once symlinks are followed. Requests that
+work on an entry itself (deleting, renaming, creating) only follow links up to
+its parent, so a symlink in the tree can be removed without touching its target.
+Once a user is known, what they may do is checked on the resolved path too.
+*/
+/// The directory the server hands out, nothing outside of it is reachable.
+#[derive(Debug, Clone)]
+pub struct Jail {
+    root: PathBuf,
+    permissions: UserPermissions,
+}
+
+impl Jail {
+    pub fn new(root: impl AsRef<Path>) -> std::io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Jail {
+            root,
+            permissions: UserPermissions::unrestricted(),
+        })
+    }
+    /// The same jail, restricted to what `permissions` allow.
+    pub fn for_user(&self, permissions: UserPermissions) -> Self {
+        Jail {
+            root: self.root.clone(),
+            permissions,
+        }
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// The path clients know `real` by.
+    pub fn to_virtual(&self, real: &Path) -> String {
+        let relative = real.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        let parts: Vec<_> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        format!("/{}", parts.join("/"))
+    }
+    /// What the user may do with `real`, a path inside the jail.
+    pub fn access(&self, real: &Path) -> Option<Access> {
+        self.permissions.access(&self.to_virtual(real))
+    }
+    /// Whether the user may change everything below `directory`, without following
+    /// symlinks.
+    pub fn writable_tree(&self, directory: &Path) -> std::io::Result<bool> {
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if self.access(&path) != Some(Access::Write) {
+                return Ok(false);
+            }
+            if entry.file_type()?.is_dir() && !self.writable_tree(&path)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    fn join(&self, current: &Path, path: &str) -> PathBuf {
+        match path.strip_prefix('/') {
+            Some(path) => self.root.join(path.trim_start_matches('/')),
+            None => current.join(path),
+        }
+    }
+    /// Resolves `path` relative to `current`, following every symlink. The path
+    /// has to exist and the user needs at least `needed` on it.
+    pub fn resolve(&self, current: &Path, path: &str, needed: Access) -> std::io::Result<PathBuf> {
+        let resolved = self.join(current, path).canonicalize()?;
+        self.check(resolved, path, needed)
+    }
+    /// Resolves `path` relative to `current` without following its last component,
+    /// which doesn't have to exist yet. The user has to be allowed to change both
+    /// the entry and the directory it is in.
+    pub fn resolve_entry(&self, current: &Path, path: &str) -> std::io::Result<PathBuf> {
+        let joined = self.join(current, path);
+        let name = match joined.components().next_back() {
+            Some(Component::Normal(name)) => name.to_owned(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{path} does not name an entry"),
+                ))
+            }
+        };
+        let parent = joined.parent().unwrap_or(&self.root).canonicalize()?;
+        let parent = self.check(parent, path, Access::Write)?;
+        self.check(parent.join(name), path, Access::Write)
+    }
+    fn check(
+        &self,
+        resolved: PathBuf,
+        requested: &str,
+        needed: Access,
+    ) -> std::io::Result<PathBuf> {
+        if !resolved.starts_with(&self.root) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{requested} is outside of the served directory"),
+            ));
+        }
+        match self.access(&resolved) {
+            Some(access) if access >= needed => Ok(resolved),
+            Some(_) if needed == Access::Write => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{requested} is read-only for you"),
+            )),
+            _ => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{requested} is not shared with you"),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permissions::Permissions;
+    use std::fs;
+
+    /// A temporary tree with `served/inner/file` inside the jail and `secret`
+    /// next to it.
+    fn tree() -> (tempfile::TempDir, Jail) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("served/inner")).unwrap();
+        fs::write(dir.path().join("served/inner/file"), "inside").unwrap();
+        fs::write(dir.path().join("secret"), "outside").unwrap();
+        let jail = Jail::new(dir.path().join("served")).unwrap();
+        (dir, jail)
+    }
+
+    fn denied(result: std::io::Result<PathBuf>) -> bool {
+        matches!(result, Err(error) if error.kind() == ErrorKind::PermissionDenied)
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_root() {
+        let (_dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        assert_eq!(
+            jail.resolve(&root, "inner/file", Access::Read).unwrap(),
+            root.join("inner/file")
+        );
+        assert_eq!(
+            jail.resolve(&root.join("inner"), "..", Access::Read)
+                .unwrap(),
+            root
+        );
+        assert_eq!(
+            jail.resolve_entry(&root, "inner/new").unwrap(),
+            root.join("inner/new")
+        );
+    }
+
+    #[test]
+    fn rejects_parent_directories_past_the_root() {
+        let (_dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        assert!(denied(jail.resolve(&root, "..", Access::Read)));
+        assert!(denied(jail.resolve(&root, "../secret", Access::Read)));
+        assert!(denied(jail.resolve(
+            &root.join("inner"),
+            "../../secret",
+            Access::Read
+        )));
+        assert!(denied(jail.resolve_entry(&root, "../secret")));
+        assert!(jail.resolve_entry(&root, "..").is_err());
+    }
+
+    #[test]
+    fn takes_absolute_paths_from_the_root() {
+        let (dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        assert_eq!(
+            jail.resolve(&root.join("inner"), "/", Access::Read)
+                .unwrap(),
+            root
+        );
+        assert_eq!(
+            jail.resolve(&root.join("inner"), "/inner/file", Access::Read)
+                .unwrap(),
+            root.join("inner/file")
+        );
+        assert_eq!(
+            jail.resolve_entry(&root.join("inner"), "//new").unwrap(),
+            root.join("new")
+        );
+        // A real path outside is looked for below the root and is not there
+        let secret = dir.path().join("secret");
+        let error = jail
+            .resolve(&root, secret.to_str().unwrap(), Access::Read)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert!(denied(jail.resolve(&root, "/../secret", Access::Read)));
+        assert!(denied(jail.resolve_entry(&root, "/../secret")));
+    }
+
+    #[test]
+    fn shows_paths_relative_to_the_root() {
+        let (_dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        assert_eq!(jail.to_virtual(&root), "/");
+        assert_eq!(jail.to_virtual(&root.join("inner/file")), "/inner/file");
+    }
+
+    #[test]
+    fn checks_what_the_user_may_do() {
+        let (dir, jail) = tree();
+        fs::write(jail.root().join("inner/id.key"), "key").unwrap();
+        fs::write(dir.path().join("permissions"), "bob ro /inner *.key\n").unwrap();
+        let permissions = Permissions::load(dir.path().join("permissions")).unwrap();
+        let bob = jail.for_user(permissions.for_user("bob"));
+        let root = jail.root().to_path_buf();
+        assert_eq!(bob.resolve(&root, "/", Access::Browse).unwrap(), root);
+        assert!(denied(bob.resolve(&root, "/", Access::Read)));
+        assert!(bob.resolve(&root, "inner/file", Access::Read).is_ok());
+        assert!(denied(bob.resolve(&root, "inner/file", Access::Write)));
+        assert!(denied(bob.resolve(&root, "inner/id.key", Access::Read)));
+        assert!(denied(bob.resolve_entry(&root, "inner/new")));
+        // Users without a line of their own only see the root
+        let eve = jail.for_user(permissions.for_user("eve"));
+        assert!(denied(eve.resolve(&root, "inner", Access::Browse)));
+    }
+
+    #[test]
+    fn merges_the_lines_of_a_user() {
+        let (dir, jail) = tree();
+        fs::create_dir(jail.root().join("public")).unwrap();
+        fs::write(jail.root().join("inner/id.key"), "key").unwrap();
+        fs::write(
+            dir.path().join("permissions"),
+            "carol ro /public,/inner\ncarol rw /inner/deeper *.key\n",
+        )
+        .unwrap();
+        let permissions = Permissions::load(dir.path().join("permissions")).unwrap();
+        let carol = jail.for_user(permissions.for_user("carol"));
+        let root = jail.root().to_path_buf();
+        assert_eq!(carol.access(&root.join("public")), Some(Access::Read));
+        assert_eq!(carol.access(&root.join("inner/file")), Some(Access::Read));
+        assert_eq!(
+            carol.access(&root.join("inner/deeper/new")),
+            Some(Access::Write)
+        );
+        // The patterns of one line apply to the roots of the others too
+        assert_eq!(carol.access(&root.join("inner/id.key")), None);
+    }
+
+    #[test]
+    fn finds_entries_that_are_off_limits_below_a_directory() {
+        let (dir, jail) = tree();
+        fs::create_dir_all(jail.root().join("inner/drafts1")).unwrap();
+        fs::write(jail.root().join("inner/drafts1/plan.txt"), "plan").unwrap();
+        fs::create_dir(jail.root().join("open")).unwrap();
+        fs::write(jail.root().join("open/notes"), "notes").unwrap();
+        fs::write(
+            dir.path().join("permissions"),
+            "alice rw / /inner/drafts*\n",
+        )
+        .unwrap();
+        let permissions = Permissions::load(dir.path().join("permissions")).unwrap();
+        let alice = jail.for_user(permissions.for_user("alice"));
+        let root = jail.root().to_path_buf();
+        // Renaming or deleting `inner` would take `drafts1` along
+        assert!(!alice.writable_tree(&root.join("inner")).unwrap());
+        assert!(alice.writable_tree(&root.join("open")).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_leading_outside() {
+        let (dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("file_link")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), root.join("dir_link")).unwrap();
+        assert!(denied(jail.resolve(&root, "file_link", Access::Read)));
+        assert!(denied(jail.resolve(&root, "dir_link", Access::Read)));
+        assert!(denied(jail.resolve(&root, "dir_link/secret", Access::Read)));
+        assert!(denied(jail.resolve_entry(&root, "dir_link/new")));
+        // The link itself lives inside, so it can still be deleted or renamed
+        assert_eq!(
+            jail.resolve_entry(&root, "file_link").unwrap(),
+            root.join("file_link")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_that_stay_inside() {
+        let (_dir, jail) = tree();
+        let root = jail.root().to_path_buf();
+        std::os::unix::fs::symlink(root.join("inner"), root.join("shortcut")).unwrap();
+        assert_eq!(
+            jail.resolve(&root, "shortcut/file", Access::Read).unwrap(),
+            root.join("inner/file")
+        );
+    }
+}
@@ -1,86 +1,446 @@
+pub mod archive;
+pub mod auth;
+pub mod compression;
+pub mod idle;
+pub mod jail;
+pub mod permissions;
+pub mod protocol;
+
 pub mod prelude {
     pub type UniversalResult<T> = Result<T, Box<dyn std::error::Error>>;
     pub fn path_exists(path: &std::path::Path) -> bool {
         path.exists()
     }
-    pub fn build_packet(data: String, seperator: char) -> Vec<u8> {
-        let result = format!("{}{seperator}{data}", data.len());
-        Vec::from(result)
+    /// Where a transfer into `path` is written until it is complete.
+    pub fn part_path(path: &std::path::Path) -> std::path::PathBuf {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        std::path::PathBuf::from(part)
     }
 }
-pub mod server {
-    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+pub mod tls {
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        RootCertStore,
+    };
+
+    use std::path::Path;
 
     use crate::prelude::UniversalResult;
-    use std::{fs::read_dir, path::PathBuf};
 
-    pub fn list_directory(directory: &PathBuf) -> UniversalResult<Vec<String>> {
-        let mut result: Vec<String> = Vec::new();
-        if directory.parent().is_some() {
-            result.push("DIR_..".to_string());
+    /// Loads a certificate chain and its private key, for the server or for a
+    /// client that has to present a certificate.
+    pub fn load_tls(
+        cert_path: impl AsRef<Path>,
+        pk_path: impl AsRef<Path>,
+    ) -> UniversalResult<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let cert_path = cert_path.as_ref();
+        let certs = CertificateDer::pem_file_iter(cert_path)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| format!("Can't read {}: {error}", cert_path.display()))?;
+        let privatekey = PrivateKeyDer::from_pem_file(pk_path)?;
+
+        Ok((certs, privatekey))
+    }
+    /// Loads the CA certificates peers have to be signed by.
+    pub fn load_certificates(certificate_path: impl AsRef<Path>) -> UniversalResult<RootCertStore> {
+        let mut root_cert_store = RootCertStore::empty();
+        let mut certificate_path = std::io::BufReader::new(std::fs::File::open(certificate_path)?);
+        for certificate in rustls_pemfile::certs(&mut certificate_path) {
+            root_cert_store.add(certificate?)?;
+        }
+        // root_cert_store.add_parsable_certificates(rustls_pemfile::certs(&mut certificate_path).map(|cert| cert.unwrap()));
+
+        Ok(root_cert_store)
+    }
+    /// Adds the CAs the operating system trusts to `store`.
+    pub fn add_system_certificates(store: &mut RootCertStore) -> UniversalResult<()> {
+        let result = rustls_native_certs::load_native_certs();
+        let (added, _) = store.add_parsable_certificates(result.certs);
+        if added == 0 {
+            let errors: Vec<String> = result.errors.iter().map(ToString::to_string).collect();
+            return Err(format!("No usable system certificates found ({})", errors.join(", ")).into());
         }
-        let files: Vec<String> = read_dir(directory)?
-            .map(|entry| {
-                let mut entry = entry.unwrap().path();
+        Ok(())
+    }
+    /// Who a (verified) client certificate belongs to: the common name of its subject.
+    pub fn certificate_identity(certificate: &CertificateDer) -> Option<String> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+        let identity = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+        Some(identity.to_string())
+    }
+}
+pub mod server {
+    use futures::{SinkExt, StreamExt};
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt},
+        net::TcpStream,
+        sync::mpsc,
+    };
+    use tokio_rustls::server::TlsStream;
+    use tokio_util::{bytes::Bytes, codec::Framed, io::StreamReader};
+
+    use crate::{
+        prelude::UniversalResult,
+        archive::write_archive,
+        compression::{Compressor, Decompressor},
+        idle::IdleTimeout,
+        jail::Jail,
+        protocol::{
+            hex_digest, Access, Compression, Entry, ErrorCode, HashingWriter, Request, Response,
+            ServerCodec, CHUNK_SIZE,
+        },
+    };
+    use std::{
+        fs::read_dir,
+        path::{Path, PathBuf},
+    };
+    pub type Connection = Framed<TlsStream<IdleTimeout<TcpStream>>, ServerCodec>;
+
+    /// Lists `directory` the way clients see it, with a `..` entry unless it is the
+    /// served root. Entries the user may not see are left out.
+    pub fn list_directory(directory: &Path, jail: &Jail) -> std::io::Result<Response> {
+        let mut result: Vec<Entry> = Vec::new();
+        if let Some(parent) = directory.parent().filter(|_| directory != jail.root()) {
+            let access = jail.access(parent).unwrap_or(Access::Browse);
+            result.push(Entry::dir("..").with_access(access));
+        }
+        let files: Vec<Entry> = read_dir(directory)?
+            .filter_map(|entry| {
+                let entry = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(error) => return Some(Err(error)),
+                };
+                let access = jail.access(&entry)?;
+                let path = jail.to_virtual(&entry);
                 if entry.is_dir() {
-                    return format!("DIR_{}", entry.as_mut_os_str().to_str().unwrap());
+                    return Some(Ok(Entry::dir(path).with_access(access)));
                 }
-                format!("FILE_{}", entry.as_mut_os_str().to_str().unwrap())
+                Some(Ok(Entry::file(path).with_access(access)))
             })
-            .collect();
+            .collect::<std::io::Result<_>>()?;
         result.extend_from_slice(&files);
 
-        Ok(result)
+        Ok(Response::Listing {
+            path: jail.to_virtual(directory),
+            access: jail.access(directory).unwrap_or(Access::Browse),
+            entries: result,
+        })
     }
-    pub fn load_tls(
-        cert_path: &str,
-        pk_path: &str,
-    ) -> UniversalResult<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let certs: Vec<CertificateDer> = CertificateDer::pem_file_iter(cert_path)?
-            .map(|cert| cert.unwrap())
-            .collect();
-        let privatekey = PrivateKeyDer::from_pem_file(pk_path)?;
-
-        Ok((certs, privatekey))
+    /// Streams everything `reader` produces as a transfer, returning the amount of
+    /// bytes sent (before compression) and, when a failing read aborted the transfer,
+    /// the response to finish it with. Only a broken connection is returned as an
+    /// error. `hasher` must already contain whatever came before `reader`
+    /// in the file.
+    pub async fn send_transfer<R: AsyncRead + Unpin>(
+        client: &mut Connection,
+        mut reader: R,
+        size: Option<u64>,
+        mut hasher: Sha256,
+        compression: Compression,
+    ) -> UniversalResult<(u64, Option<Response>)> {
+        let mut compressor = match Compressor::new(compression) {
+            Ok(compressor) => compressor,
+            Err(error) => return Ok((0, Some(Response::from(error)))),
+        };
+        client
+            .send(Response::Transfer { size, compression })
+            .await?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut sent = 0;
+        loop {
+            let compressed = match reader.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    hasher.update(&chunk[..read]);
+                    sent += read as u64;
+                    compressor.compress(&chunk[..read])
+                }
+                Err(error) => Err(error),
+            };
+            match compressed {
+                // The compressor holds on to small inputs until it has a block worth sending
+                Ok(compressed) if compressed.is_empty() => {}
+                Ok(compressed) => client.send(Response::Data(compressed)).await?,
+                Err(error) => return Ok((sent, Some(Response::from(error)))),
+            }
+        }
+        match compressor.finish() {
+            Ok(rest) if rest.is_empty() => {}
+            Ok(rest) => client.send(Response::Data(rest)).await?,
+            Err(error) => return Ok((sent, Some(Response::from(error)))),
+        }
+        let sha256 = hex_digest(hasher);
+        client.send(Response::TransferEnd { sha256 }).await?;
+        Ok((sent, None))
+    }
+    /// Writes the data of an upload into `writer` until the client ends it, returning
+    /// the amount of bytes stored. The inner error is the response to finish the upload
+    /// with, the connection is ready for the next request by then. Only a broken
+    /// connection or a client that stops mid-upload is returned as an outer error.
+    pub async fn receive_transfer<W: Write>(
+        client: &mut Connection,
+        writer: W,
+        compression: Compression,
+    ) -> UniversalResult<Result<u64, Response>> {
+        let mut output = Decompressor::new(compression, HashingWriter::new(writer, Sha256::new()))?;
+        // A local write error must not leave the rest of the upload in the socket
+        let mut write_error: Option<std::io::Error> = None;
+        loop {
+            match client.next().await {
+                Some(Ok(Request::Data(chunk))) => {
+                    if write_error.is_none() {
+                        if let Err(error) = output.write_all(&chunk) {
+                            write_error = Some(error);
+                        }
+                    }
+                }
+                Some(Ok(Request::TransferEnd { sha256 })) => {
+                    if let Some(error) = write_error {
+                        return Ok(Err(Response::from(error)));
+                    }
+                    let output = match output.finish().and_then(|mut output| {
+                        output.inner.flush()?;
+                        Ok(output)
+                    }) {
+                        Ok(output) => output,
+                        Err(error) => return Ok(Err(Response::from(error))),
+                    };
+                    let actual = hex_digest(output.hasher);
+                    if actual != sha256 {
+                        return Ok(Err(Response::error(
+                            ErrorCode::ChecksumMismatch,
+                            format!("the client sent sha256 {sha256} but we got {actual}"),
+                        )));
+                    }
+                    return Ok(Ok(output.written));
+                }
+                Some(Ok(Request::Abort)) => {
+                    return Ok(Err(Response::error(
+                        ErrorCode::InvalidRequest,
+                        "the upload was aborted",
+                    )))
+                }
+                Some(Ok(request)) => {
+                    return Err(format!("Expected the data of an upload, got {request:?}").into())
+                }
+                Some(Err(error)) => return Err(error.into()),
+                None => return Err("Client closed the connection during an upload".into()),
+            }
+        }
+    }
+    /// Builds a tar of `directory` on a blocking thread while it is being read, so
+    /// the archive never has to fit in memory. A failure half way comes out of the
+    /// reader as an error. Only what the user may read goes in.
+    pub fn stream_archive(directory: PathBuf, jail: Jail) -> impl AsyncRead + Unpin + Send {
+        let (sender, mut receiver) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let result = write_archive(
+                |chunk| sender.blocking_send(Ok(Bytes::from(chunk))),
+                |builder| {
+                    // A symlink could lead out of the served directory
+                    builder.follow_symlinks(false);
+                    append_readable(builder, &jail, &directory, Path::new(""))
+                },
+            );
+            if let Err(error) = result {
+                let _ = sender.blocking_send(Err(error));
+            }
+        });
+        StreamReader::new(futures::stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+    }
+    fn append_readable<W: Write>(
+        builder: &mut tar::Builder<W>,
+        jail: &Jail,
+        directory: &Path,
+        name: &Path,
+    ) -> std::io::Result<()> {
+        for entry in read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            if jail.access(&path) < Some(Access::Read) {
+                continue;
+            }
+            let name = name.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                builder.append_dir(&name, &path)?;
+                append_readable(builder, jail, &path, &name)?;
+            } else {
+                builder.append_path_with_name(&path, &name)?;
+            }
+        }
+        Ok(())
     }
 }
 
 pub mod client {
-    use std::path::PathBuf;
-
+    use futures::{SinkExt, StreamExt};
+    use sha2::{Digest, Sha256};
+    use std::{
+        fmt,
+        fs::{File, OpenOptions},
+        io::{Read, Write},
+        path::Path,
+    };
     use ratatui::{
         crossterm::event,
         layout::{Alignment, Constraint, Direction, Layout},
         style::{Color, Modifier, Style, Stylize},
         text::{Line, Span, Text},
-        widgets::{Block, Paragraph, Widget},
+        widgets::{Block, Borders, Clear, Paragraph, Widget, Wrap},
         DefaultTerminal,
     };
-    use rustls::RootCertStore;
     use syntect::{
         easy::HighlightLines, highlighting::ThemeSet, parsing::SyntaxSet, util::LinesWithEndings,
     };
-    use tokio::{io::{split, AsyncReadExt}, net::TcpStream};
+    use tokio::net::TcpStream;
     use tokio_rustls::client::TlsStream;
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        archive::pack,
+        compression::{Compressor, Decompressor},
+        prelude::{part_path, UniversalResult},
+        protocol::{
+            hex_digest, Access, Capability, ClientCodec, Compression, Entry, Existing, HashingWriter,
+            ListingParts, RemoteError, Request, Response, CHUNK_SIZE, MAX_LISTING_SIZE,
+            PROTOCOL_VERSION,
+        },
+    };
+    pub type Connection = Framed<TlsStream<TcpStream>, ClientCodec>;
 
-    use crate::prelude::UniversalResult;
     pub fn get_screen_size() -> (u16, u16) {
         ratatui::crossterm::terminal::size().unwrap()
     }
-    pub async fn calculate_packet_size(
-        client: &mut TlsStream<TcpStream>,
-    ) -> UniversalResult<usize> {
-        Ok({
-            let mut buffer: [u8; 1] = [0];
-            let mut content_len = String::new();
-            while buffer[0] != b'\r' {
-                client.read_exact(&mut buffer).await?;
-                content_len.push(buffer[0] as char);
+    pub async fn send_request(
+        client: &mut Connection,
+        request: Request,
+    ) -> UniversalResult<Response> {
+        client.send(request).await?;
+        read_response(client).await
+    }
+    /// The next response, a listing that came in parts is handed back whole as long
+    /// as it stays below MAX_LISTING_SIZE.
+    pub async fn read_response(client: &mut Connection) -> UniversalResult<Response> {
+        let mut parts = ListingParts::new(MAX_LISTING_SIZE);
+        loop {
+            let response = match client.next().await {
+                Some(response) => response?,
+                None => return Err("Server closed the connection".into()),
+            };
+            match response {
+                Response::ListingPart(entries) => parts.add(entries)?,
+                Response::Listing { path, access, entries } if !parts.is_empty() => {
+                    let entries = parts.finish(entries);
+                    return Ok(Response::Listing { path, access, entries });
+                }
+                response => return Ok(response),
             }
-            content_len.pop();
+        }
+    }
+    /// What we received does not hash to what the server sent.
+    #[derive(Debug)]
+    pub struct ChecksumMismatch {
+        pub expected: String,
+        pub actual: String,
+    }
 
-            content_len.parse()?
-        })
+    impl fmt::Display for ChecksumMismatch {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "Checksum mismatch, the server sent sha256 {} but we got {}",
+                self.expected, self.actual
+            )
+        }
+    }
+
+    impl std::error::Error for ChecksumMismatch {}
+
+    /// Sends `request` and writes the transfer the server answers with into `writer`,
+    /// returning the amount of bytes received. Errors reported by the server come back
+    /// as a `RemoteError`, a corrupted transfer as a `ChecksumMismatch`.
+    pub async fn download<W: Write>(
+        client: &mut Connection,
+        request: Request,
+        writer: &mut W,
+    ) -> UniversalResult<u64> {
+        download_with_hasher(client, request, writer, Sha256::new()).await
+    }
+    /// Like `download`, `hasher` holds the part of the file received earlier.
+    pub async fn download_with_hasher<W: Write>(
+        client: &mut Connection,
+        request: Request,
+        writer: &mut W,
+        hasher: Sha256,
+    ) -> UniversalResult<u64> {
+        let compression = match send_request(client, request).await? {
+            Response::Transfer { compression, .. } => compression,
+            Response::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            other => return Err(unexpected_response(other)),
+        };
+        let mut output = Decompressor::new(compression, HashingWriter::new(writer, hasher))?;
+        // A local write error must not leave the rest of the transfer in the socket
+        let mut write_error: Option<std::io::Error> = None;
+        loop {
+            match read_response(client).await? {
+                Response::Data(chunk) => {
+                    if write_error.is_none() {
+                        if let Err(error) = output.write_all(&chunk) {
+                            write_error = Some(error);
+                        }
+                    }
+                }
+                Response::TransferEnd { sha256 } => {
+                    if let Some(error) = write_error {
+                        return Err(error.into());
+                    }
+                    let output = output.finish()?;
+                    output.inner.flush()?;
+                    let actual = hex_digest(output.hasher);
+                    if actual != sha256 {
+                        return Err(ChecksumMismatch {
+                            expected: sha256,
+                            actual,
+                        }
+                        .into());
+                    }
+                    return Ok(output.written);
+                }
+                Response::Error { code, message } => {
+                    return Err(RemoteError { code, message }.into())
+                }
+                other => return Err(unexpected_response(other)),
+            }
+        }
+    }
+    /// Sends our `Hello` and returns the capabilities the server agreed on.
+    pub async fn handshake(
+        client: &mut Connection,
+        capabilities: &[Capability],
+    ) -> UniversalResult<Vec<Capability>> {
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.to_vec(),
+        };
+        match send_request(client, hello).await? {
+            Response::Hello {
+                version,
+                capabilities,
+            } if version == PROTOCOL_VERSION => Ok(capabilities),
+            Response::Hello { version, .. } | Response::UnsupportedVersion { supported: version } => {
+                Err(format!(
+                    "Server speaks protocol version {version} but this client speaks version {PROTOCOL_VERSION}, please update"
+                )
+                .into())
+            }
+            other => Err(unexpected_response(other)),
+        }
+    }
+    pub fn unexpected_response(response: Response) -> Box<dyn std::error::Error> {
+        format!("Unexpected response from server: {response:?}").into()
     }
     pub fn print_file(
         terminal: &mut DefaultTerminal,
@@ -153,23 +513,22 @@ pub mod client {
         })?;
         Ok(())
     }
-    pub fn load_certificates(certificate_path: &str) -> UniversalResult<RootCertStore> {
-        let mut root_cert_store = RootCertStore::empty();
-        let mut certificate_path = std::io::BufReader::new(std::fs::File::open(certificate_path)?);
-        for certificate in rustls_pemfile::certs(&mut certificate_path) {
-            root_cert_store.add(certificate?)?;
-        }
-        // root_cert_store.add_parsable_certificates(rustls_pemfile::certs(&mut certificate_path).map(|cert| cert.unwrap()));
-
-        Ok(root_cert_store)
-    }
-
-    pub fn unwrap_empty_string(data_decrypted: String, seperator: &str) -> Vec<PathBuf> {
-        data_decrypted.split(seperator).map(PathBuf::from).collect()
+    /// `/docs/2024` as `/ > docs > 2024`.
+    fn breadcrumb(path: &str) -> String {
+        std::iter::once("/")
+            .chain(path.split('/').filter(|part| !part.is_empty()))
+            .collect::<Vec<_>>()
+            .join(" > ")
     }
+    /// Shows the entries of the remote directory `path` below a breadcrumb line.
+    /// Draws the listing between the breadcrumb and a line of the keys that change
+    /// things, greyed out where `access` (to the directory) or the selected entry
+    /// don't allow them.
     pub fn print_directory(
         terminal: &mut DefaultTerminal,
-        entries: &[PathBuf],
+        path: &str,
+        access: Access,
+        entries: &[Entry],
         currently_selected: usize,
         pointing_to_begin: usize,
     ) -> UniversalResult<()> {
@@ -178,21 +537,47 @@ pub mod client {
             if index < pointing_to_begin {
                 continue;
             }
-            let entry_str = entry.to_str().unwrap();
-            let (prefix, mut style) = if entry_str.starts_with("DIR_") {
-                ("DIR_", Style::default().fg(Color::White))
+            let mut style = if entry.is_dir() {
+                Style::default().fg(Color::White)
             } else {
-                ("FILE_", Style::default().fg(Color::Yellow))
+                Style::default().fg(Color::Yellow)
             };
             if index == currently_selected {
                 style = style.bg(Color::LightGreen).fg(Color::White);
             }
-            let line = Line::styled(entry_str.strip_prefix(prefix).unwrap(), style)
-                .alignment(Alignment::Left);
+            let name = if entry.is_dir() && entry.path != ".." {
+                format!("{}/", entry.name())
+            } else {
+                entry.name().to_string()
+            };
+            let line = Line::styled(name, style).alignment(Alignment::Left);
             lines.push(line);
         }
+        let selected = entries.get(currently_selected).map_or(Access::Browse, |entry| entry.access);
+        let keys: Vec<Span> = [
+            ("s", "save", selected >= Access::Read),
+            ("u", "upload", access == Access::Write),
+            ("m", "new folder", access == Access::Write),
+            ("r", "rename", access == Access::Write && selected == Access::Write),
+            ("d", "delete", access == Access::Write && selected == Access::Write),
+        ]
+        .into_iter()
+        .map(|(key, action, allowed)| {
+            let color = if allowed { Color::White } else { Color::DarkGray };
+            Span::styled(format!("{key} {action}  "), Style::default().fg(color))
+        })
+        .collect();
         terminal.draw(|frame| {
-            frame.render_widget(Paragraph::new(Text::from(lines)), frame.area());
+            let layout = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)])
+                .split(frame.area());
+            frame.render_widget(
+                Paragraph::new(breadcrumb(path)).bold().fg(Color::Cyan),
+                layout[0],
+            );
+            frame.render_widget(Paragraph::new(Text::from(lines)), layout[1]);
+            frame.render_widget(Paragraph::new(Line::from(keys)), layout[2]);
             // frame.render_widget(Paragraph::from(), frame.area());
         })?;
         Ok(())
@@ -202,6 +587,22 @@ pub mod client {
         terminal: &mut DefaultTerminal,
         _title: Option<String>,
         default_val: Option<String>,
+    ) -> UniversalResult<String> {
+        input_field(terminal, _title, default_val, false)
+    }
+    /// Like `draw_input_field` but shows `*` instead of what is typed, and `q` is
+    /// just another character.
+    pub fn draw_password_field(
+        terminal: &mut DefaultTerminal,
+        _title: Option<String>,
+    ) -> UniversalResult<String> {
+        input_field(terminal, _title, None, true)
+    }
+    fn input_field(
+        terminal: &mut DefaultTerminal,
+        _title: Option<String>,
+        default_val: Option<String>,
+        masked: bool,
     ) -> UniversalResult<String> {
         let mut content = {
             let mut x = String::new();
@@ -226,7 +627,12 @@ pub mod client {
                     .split(frame.area());
 
                 // Create the input bar with the current input
-                let input_bar = Paragraph::new(content_clone.clone())
+                let shown = if masked {
+                    "*".repeat(content_clone.chars().count())
+                } else {
+                    content_clone.clone()
+                };
+                let input_bar = Paragraph::new(shown)
                     .style(Style::default().bg(Color::Green).fg(Color::White))
                     .block(Block::default().style(Style::default().bg(Color::Green)));
 
@@ -239,7 +645,7 @@ pub mod client {
             })?;
             if let event::Event::Key(key) = event::read()? {
                 match key.code {
-                    event::KeyCode::Char('q') => {
+                    event::KeyCode::Char('q') if !masked => {
                         break;
                     }
                     event::KeyCode::Enter => break,
@@ -256,6 +662,240 @@ pub mod client {
         }
         Ok(content)
     }
+    /// Shows an error the server sent back on top of the current screen until a key is pressed.
+    pub fn draw_error_popup(
+        terminal: &mut DefaultTerminal,
+        code: impl fmt::Display,
+        message: &str,
+    ) -> UniversalResult<()> {
+        loop {
+            terminal.draw(|frame| {
+                let horizontal_mid = Layout::new(
+                    Direction::Horizontal,
+                    vec![
+                        Constraint::Percentage(20),
+                        Constraint::Percentage(60),
+                        Constraint::Percentage(20),
+                    ],
+                )
+                .split(frame.area())[1];
+                let popup = Layout::new(
+                    Direction::Vertical,
+                    vec![
+                        Constraint::Percentage(35),
+                        Constraint::Percentage(30),
+                        Constraint::Percentage(35),
+                    ],
+                )
+                .split(horizontal_mid)[1];
+                let text = Text::from(vec![
+                    Line::from(message.to_string()),
+                    Line::from(""),
+                    Line::from("(press anything to dismiss)").italic(),
+                ]);
+                frame.render_widget(Clear, popup);
+                frame.render_widget(
+                    Paragraph::new(text)
+                        .centered()
+                        .wrap(Wrap { trim: true })
+                        .block(
+                            Block::new()
+                                .borders(Borders::ALL)
+                                .title(format!(" Error: {code} "))
+                                .title_alignment(Alignment::Center),
+                        )
+                        .fg(Color::White)
+                        .bg(Color::Red),
+                    popup,
+                );
+            })?;
+            if let event::Event::Key(key) = event::read()? {
+                if key.kind == event::KeyEventKind::Press {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Downloads the remote file `remote` to `local`. Data goes to `<local>.part` first
+    /// and is only renamed to `local` once complete. With `resume` a leftover `.part`
+    /// is continued from its length instead of starting over.
+    pub async fn download_file(
+        client: &mut Connection,
+        remote: &str,
+        local: &Path,
+        resume: bool,
+    ) -> UniversalResult<u64> {
+        let part = part_path(local);
+        let offset = match std::fs::metadata(&part) {
+            Ok(metadata) if resume => metadata.len(),
+            _ => 0,
+        };
+        let mut hasher = Sha256::new();
+        if offset > 0 {
+            std::io::copy(&mut File::open(&part)?, &mut hasher)?;
+        }
+        let (mut file, request) = if offset > 0 {
+            (
+                OpenOptions::new().append(true).open(&part)?,
+                Request::ReadRange {
+                    path: remote.to_string(),
+                    offset,
+                    length: None,
+                },
+            )
+        } else {
+            (File::create(&part)?, Request::File(remote.to_string()))
+        };
+        match download_with_hasher(client, request, &mut file, hasher).await {
+            Ok(received) => {
+                std::fs::rename(&part, local)?;
+                Ok(offset + received)
+            }
+            Err(error) => {
+                // Only keep the .part around if there is a chance to resume it
+                if error.is::<RemoteError>() || error.is::<ChecksumMismatch>() {
+                    std::fs::remove_file(&part)?;
+                }
+                Err(error)
+            }
+        }
+    }
+    /// Sends `request` (a `Put`) and streams everything `reader` produces as its data,
+    /// returning the amount of bytes sent. `compression` has to match the one named
+    /// in the request. Errors reported by the server come back as a `RemoteError`.
+    pub async fn upload<R: Read>(
+        client: &mut Connection,
+        request: Request,
+        reader: &mut R,
+        compression: Compression,
+    ) -> UniversalResult<u64> {
+        match send_request(client, request).await? {
+            Response::Ready => {}
+            Response::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            other => return Err(unexpected_response(other)),
+        }
+        let mut compressor = Compressor::new(compression)?;
+        let mut hasher = Sha256::new();
+        let mut sent = 0u64;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        // A local read error must still end the upload, the server is waiting for it
+        let mut read_error: Option<std::io::Error> = None;
+        loop {
+            let compressed = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => {
+                    hasher.update(&chunk[..read]);
+                    sent += read as u64;
+                    compressor.compress(&chunk[..read])
+                }
+                Err(error) => Err(error),
+            };
+            match compressed {
+                Ok(compressed) if compressed.is_empty() => {}
+                Ok(compressed) => client.send(Request::Data(compressed)).await?,
+                Err(error) => {
+                    read_error = Some(error);
+                    break;
+                }
+            }
+        }
+        if read_error.is_none() {
+            match compressor.finish() {
+                Ok(rest) if rest.is_empty() => {}
+                Ok(rest) => client.send(Request::Data(rest)).await?,
+                Err(error) => read_error = Some(error),
+            }
+        }
+        if let Some(error) = read_error {
+            client.send(Request::Abort).await?;
+            // The server acknowledges the abort with an error we don't need
+            read_response(client).await?;
+            return Err(error.into());
+        }
+        let sha256 = hex_digest(hasher);
+        match send_request(client, Request::TransferEnd { sha256 }).await? {
+            Response::Done => Ok(sent),
+            Response::Error { code, message } => Err(RemoteError { code, message }.into()),
+            other => Err(unexpected_response(other)),
+        }
+    }
+    /// Uploads the local file `local` as `remote`, which is relative to the directory
+    /// the session is in. Without `overwrite` an existing remote file is an error.
+    pub async fn upload_file(
+        client: &mut Connection,
+        local: &Path,
+        remote: &str,
+        overwrite: bool,
+        compression: Compression,
+    ) -> UniversalResult<u64> {
+        let mut file = File::open(local)?;
+        let request = Request::Put {
+            path: remote.to_string(),
+            overwrite,
+            compression,
+        };
+        upload(client, request, &mut file, compression).await
+    }
+    /// Uploads the local directory `local` into `remote` as a tar, packing it while it
+    /// is sent. `existing` decides what happens to entries already on the server.
+    pub async fn upload_dir(
+        client: &mut Connection,
+        local: &Path,
+        remote: &str,
+        existing: Existing,
+        compression: Compression,
+    ) -> UniversalResult<u64> {
+        if !local.is_dir() {
+            return Err(format!("{} is not a directory", local.display()).into());
+        }
+        let request = Request::PutDir {
+            path: remote.to_string(),
+            existing,
+            compression,
+        };
+        upload(client, request, &mut pack(local.to_path_buf()), compression).await
+    }
+    /// Moves the session to the remote directory `path` and returns where it ended
+    /// up along with what is in there.
+    pub async fn list_remote(
+        client: &mut Connection,
+        path: &str,
+    ) -> UniversalResult<(String, Access, Vec<Entry>)> {
+        match send_request(client, Request::Dir(path.to_string())).await? {
+            Response::Listing { path, access, entries } => Ok((path, access, entries)),
+            Response::Error { code, message } => Err(RemoteError { code, message }.into()),
+            other => Err(unexpected_response(other)),
+        }
+    }
+    /// Sends a change to the remote tree (`Mkdir`, `Rename` or `Delete`) and returns
+    /// the listing of the current directory afterwards.
+    pub async fn change_remote(
+        client: &mut Connection,
+        request: Request,
+    ) -> UniversalResult<(String, Access, Vec<Entry>)> {
+        match send_request(client, request).await? {
+            Response::Done => {}
+            Response::Error { code, message } => return Err(RemoteError { code, message }.into()),
+            other => return Err(unexpected_response(other)),
+        }
+        list_remote(client, ".").await
+    }
+    /// Errors that leave the session usable (reported by the server or a failed
+    /// checksum) become a popup, anything else is handed back.
+    pub fn draw_remote_error(
+        terminal: &mut DefaultTerminal,
+        error: Box<dyn std::error::Error>,
+    ) -> UniversalResult<()> {
+        let error = match error.downcast::<RemoteError>() {
+            Ok(error) => return draw_error_popup(terminal, error.code, &error.message),
+            Err(error) => error,
+        };
+        match error.downcast::<ChecksumMismatch>() {
+            Ok(error) => draw_error_popup(terminal, "ChecksumMismatch", &error.to_string()),
+            Err(error) => Err(error),
+        }
+    }
     pub fn block_to_continue<T: Widget + Clone>(
         widget: T,
         terminal: &mut DefaultTerminal,
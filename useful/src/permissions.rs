@@ -0,0 +1,205 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::protocol::Access;
+
+/*
+The permissions file has one line per user:
+
+    <user> <ro|rw> <root>[,<root>...] [<pattern>[,<pattern>...]]
+
+Roots are directories below the served one (`/` being all of it) the user may
+read, and with `rw` also change. Anything matching a pattern is off limits even
+inside a root: a pattern without `/` is matched against every name along the
+path (`*.key`, `.git`), one with `/` against the path itself and its parents
+(`/public/drafts*`). `*` stands for any number of characters, `?` for one.
+A user can have several lines, e.g. one `ro` and one `rw`: each root keeps the
+mode of its line and the patterns of all of them apply everywhere.
+The user `*` applies to everyone without a line of their own, users that match
+no line can log in but see nothing. Empty lines and lines starting with `#` are
+skipped.
+*/
+/// Who may do what, as read from the permissions file.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    users: HashMap<String, UserPermissions>,
+}
+
+impl Permissions {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, permissions) = parse_line(line).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{} is not a valid `user ro|rw roots [patterns]` line",
+                        path.display(),
+                        number + 1
+                    ),
+                )
+            })?;
+            users
+                .entry(username.to_string())
+                .and_modify(|merged: &mut UserPermissions| {
+                    merged.roots.extend(permissions.roots.iter().cloned());
+                    merged.deny.extend(permissions.deny.iter().cloned());
+                })
+                .or_insert(permissions);
+        }
+        Ok(Permissions { users })
+    }
+    /// Everyone may change everything, used when there is no permissions file.
+    pub fn unrestricted() -> Self {
+        Permissions {
+            users: HashMap::from([("*".to_string(), UserPermissions::unrestricted())]),
+        }
+    }
+    pub fn for_user(&self, username: &str) -> UserPermissions {
+        self.users
+            .get(username)
+            .or_else(|| self.users.get("*"))
+            .cloned()
+            .unwrap_or(UserPermissions {
+                roots: vec![],
+                deny: vec![],
+            })
+    }
+}
+
+fn parse_line(line: &str) -> Option<(&str, UserPermissions)> {
+    let mut fields = line.split_whitespace();
+    let username = fields.next()?;
+    let write = match fields.next()? {
+        "ro" => false,
+        "rw" => true,
+        _ => return None,
+    };
+    let roots = fields
+        .next()?
+        .split(',')
+        .map(|root| {
+            let root = root.trim_end_matches('/');
+            let root = match root.is_empty() {
+                true => "/".to_string(),
+                false => root.starts_with('/').then(|| root.to_string())?,
+            };
+            Some(Root { path: root, write })
+        })
+        .collect::<Option<_>>()?;
+    let deny = fields
+        .next()
+        .map(|patterns| patterns.split(',').map(str::to_string).collect())
+        .unwrap_or_default();
+    match fields.next() {
+        Some(_) => None,
+        None => Some((username, UserPermissions { roots, deny })),
+    }
+}
+
+/// What a single user may do, by virtual path.
+#[derive(Debug, Clone)]
+pub struct UserPermissions {
+    roots: Vec<Root>,
+    deny: Vec<String>,
+}
+
+/// A directory the user may read, and change if `write` is set.
+#[derive(Debug, Clone)]
+struct Root {
+    path: String,
+    write: bool,
+}
+
+impl UserPermissions {
+    pub fn unrestricted() -> Self {
+        UserPermissions {
+            roots: vec![Root {
+                path: "/".to_string(),
+                write: true,
+            }],
+            deny: vec![],
+        }
+    }
+    /// What the user may do with the entry at the virtual `path`, nothing at all
+    /// if they may not even see it. The served root can always be listed.
+    pub fn access(&self, path: &str) -> Option<Access> {
+        let mut within = self
+            .roots
+            .iter()
+            .filter(|root| is_within(path, &root.path))
+            .peekable();
+        if self.denies(path) {
+            None
+        } else if within.peek().is_some() {
+            // Roots can be nested, a `rw` one wins over a `ro` one
+            Some(match within.any(|root| root.write) {
+                true => Access::Write,
+                false => Access::Read,
+            })
+        } else if path == "/" || self.roots.iter().any(|root| is_within(&root.path, path)) {
+            Some(Access::Browse)
+        } else {
+            None
+        }
+    }
+    fn denies(&self, path: &str) -> bool {
+        let mut prefix = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            prefix.push('/');
+            prefix.push_str(name);
+            let denied = self.deny.iter().any(|pattern| match pattern.contains('/') {
+                true => matches(pattern, &prefix),
+                false => matches(pattern, name),
+            });
+            if denied {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Whether the virtual `path` is `directory` or below it.
+fn is_within(path: &str, directory: &str) -> bool {
+    directory == "/"
+        || path == directory
+        || path
+            .strip_prefix(directory)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Wildcard matching, `*` for any number of characters and `?` for one.
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it swallowed
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
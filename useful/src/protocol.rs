@@ -0,0 +1,1213 @@
+use std::{fmt, marker::PhantomData};
+
+use sha2::{Digest, Sha256};
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+/*
+Every frame on the wire looks like `<len>\r<body>` where `<len>` is the decimal
+length of `<body>`. The body starts with a tag, optionally followed by `_` and
+the payload, e.g. `FILE_/some/path` or `SHUTDOWN`.
+*/
+const SEPARATOR: u8 = b'\r';
+const TAG_SEPARATOR: u8 = b'_';
+/// Digits in the largest `<len>` there can be, anything longer is garbage.
+const MAX_LENGTH_DIGITS: usize = 20;
+
+/// Bumped whenever a change to the protocol would make an older peer misparse frames.
+pub const PROTOCOL_VERSION: u32 = 9;
+/// Payload size of a single `Data` frame in a transfer.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Largest frame body a codec accepts unless it is told otherwise.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+/// The lowest limit every frame still fits in, `Data` and listings are split into
+/// pieces of about CHUNK_SIZE before they are sent.
+pub const MIN_FRAME_SIZE: usize = 2 * CHUNK_SIZE;
+/// Most a listing sent in parts may add up to, counting what its entries take up
+/// in memory (their paths plus the `Entry` itself).
+pub const MAX_LISTING_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(std::io::Error),
+    InvalidLength(String),
+    /// The peer announced a frame above the limit, it is not read.
+    FrameTooLarge {
+        length: usize,
+        max: usize,
+    },
+    /// The parts of a listing went past MAX_LISTING_SIZE.
+    ListingTooLarge,
+    UnknownTag(String),
+    Malformed(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(error) => write!(f, "{error}"),
+            ProtocolError::InvalidLength(len) => write!(f, "invalid frame length {len:?}"),
+            ProtocolError::FrameTooLarge { length, max } => {
+                write!(
+                    f,
+                    "frame of {length} bytes is larger than the limit of {max}"
+                )
+            }
+            ProtocolError::ListingTooLarge => {
+                write!(f, "listing is larger than {MAX_LISTING_SIZE} bytes")
+            }
+            ProtocolError::UnknownTag(tag) => write!(f, "unknown message {tag:?}"),
+            ProtocolError::Malformed(reason) => write!(f, "malformed message: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(error: std::io::Error) -> Self {
+        ProtocolError::Io(error)
+    }
+}
+
+/// What the logged in user may do with an entry, each level allowing everything
+/// the ones before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    /// Only lies on the way to something shared, can be listed but not read.
+    Browse,
+    Read,
+    /// Can be uploaded to, created, renamed and deleted.
+    Write,
+}
+
+impl Access {
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Browse => "browse",
+            Access::Read => "read",
+            Access::Write => "write",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "browse" => Some(Access::Browse),
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Dir,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub path: String,
+    pub access: Access,
+}
+
+impl Entry {
+    pub fn dir(path: impl Into<String>) -> Self {
+        Entry {
+            kind: EntryKind::Dir,
+            path: path.into(),
+            access: Access::Browse,
+        }
+    }
+    pub fn file(path: impl Into<String>) -> Self {
+        Entry {
+            kind: EntryKind::File,
+            path: path.into(),
+            access: Access::Browse,
+        }
+    }
+    pub fn with_access(self, access: Access) -> Self {
+        Entry { access, ..self }
+    }
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Dir
+    }
+    /// The last part of the path, what a listing shows.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// Gathers the entries of a listing that is sent in parts, as long as they stay
+/// below a size. Each entry counts with what it takes up in memory, so a flood of
+/// entries with empty paths runs into the limit as well.
+pub struct ListingParts {
+    entries: Vec<Entry>,
+    size: usize,
+    max_size: usize,
+}
+
+impl ListingParts {
+    pub fn new(max_size: usize) -> Self {
+        ListingParts {
+            entries: vec![],
+            size: 0,
+            max_size,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Adds the entries of a `ListingPart`, failing once the listing gets too large.
+    pub fn add(&mut self, entries: Vec<Entry>) -> Result<(), ProtocolError> {
+        self.size += entries
+            .iter()
+            .map(|entry| std::mem::size_of::<Entry>() + entry.path.len())
+            .sum::<usize>();
+        if self.size > self.max_size {
+            return Err(ProtocolError::ListingTooLarge);
+        }
+        self.entries.extend(entries);
+        Ok(())
+    }
+    /// The whole listing, given the entries of the `Listing` that ends it.
+    pub fn finish(mut self, mut last: Vec<Entry>) -> Vec<Entry> {
+        self.entries.append(&mut last);
+        self.entries
+    }
+}
+
+/// Why the server could not carry out a request, sent along with a human readable message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NotFound,
+    PermissionDenied,
+    IsADirectory,
+    NotADirectory,
+    AlreadyExists,
+    ChecksumMismatch,
+    AuthenticationFailed,
+    InvalidRequest,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NotFound",
+            ErrorCode::PermissionDenied => "PermissionDenied",
+            ErrorCode::IsADirectory => "IsADirectory",
+            ErrorCode::NotADirectory => "NotADirectory",
+            ErrorCode::AlreadyExists => "AlreadyExists",
+            ErrorCode::ChecksumMismatch => "ChecksumMismatch",
+            ErrorCode::AuthenticationFailed => "AuthenticationFailed",
+            ErrorCode::InvalidRequest => "InvalidRequest",
+            ErrorCode::Internal => "Internal",
+        }
+    }
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "NotFound" => ErrorCode::NotFound,
+            "PermissionDenied" => ErrorCode::PermissionDenied,
+            "IsADirectory" => ErrorCode::IsADirectory,
+            "NotADirectory" => ErrorCode::NotADirectory,
+            "AlreadyExists" => ErrorCode::AlreadyExists,
+            "ChecksumMismatch" => ErrorCode::ChecksumMismatch,
+            "AuthenticationFailed" => ErrorCode::AuthenticationFailed,
+            "InvalidRequest" => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl From<std::io::ErrorKind> for ErrorCode {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        match kind {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            std::io::ErrorKind::IsADirectory => ErrorCode::IsADirectory,
+            std::io::ErrorKind::NotADirectory => ErrorCode::NotADirectory,
+            std::io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData => {
+                ErrorCode::InvalidRequest
+            }
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Lowercase hex SHA-256, as carried by `TransferEnd`.
+pub fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Hashes and counts everything that goes through to `inner`.
+pub struct HashingWriter<W: std::io::Write> {
+    pub inner: W,
+    pub hasher: Sha256,
+    pub written: u64,
+}
+
+impl<W: std::io::Write> HashingWriter<W> {
+    /// `hasher` may already hold whatever came before in the file.
+    pub fn new(inner: W, hasher: Sha256) -> Self {
+        HashingWriter {
+            inner,
+            hasher,
+            written: 0,
+        }
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How the `Data` chunks of a transfer are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "zstd" => Some(Compression::Zstd),
+            "gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+}
+
+/// What a `PutDir` does with entries that are already on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    Overwrite,
+    Skip,
+}
+
+impl Existing {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Existing::Overwrite => "overwrite",
+            Existing::Skip => "skip",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "overwrite" => Some(Existing::Overwrite),
+            "skip" => Some(Existing::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// A `Response::Error` the server sent, as a value that can travel through `?`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Optional features a peer announces in its `Hello`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capability {
+    /// Transfers may be compressed with zstd.
+    Zstd,
+    /// Transfers may be compressed with gzip.
+    Gzip,
+    Upload,
+    Resume,
+    /// The client was identified by its TLS certificate and skips the login.
+    ClientCertificate,
+    /// Announced by a newer peer, we don't know what it means.
+    Unknown(String),
+}
+
+impl Capability {
+    pub fn name(&self) -> &str {
+        match self {
+            Capability::Zstd => "zstd",
+            Capability::Gzip => "gzip",
+            Capability::Upload => "upload",
+            Capability::Resume => "resume",
+            Capability::ClientCertificate => "clientcert",
+            Capability::Unknown(name) => name,
+        }
+    }
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "zstd" => Capability::Zstd,
+            "gzip" => Capability::Gzip,
+            "upload" => Capability::Upload,
+            "resume" => Capability::Resume,
+            "clientcert" => Capability::ClientCertificate,
+            name => Capability::Unknown(name.to_string()),
+        }
+    }
+}
+
+/// The capabilities both sides announced, in the order of `ours`.
+pub fn negotiate(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .filter(|capability| {
+            !matches!(capability, Capability::Unknown(_)) && theirs.contains(capability)
+        })
+        .cloned()
+        .collect()
+}
+
+fn encode_hello(dst: &mut BytesMut, version: u32, capabilities: &[Capability]) {
+    let capabilities: Vec<&str> = capabilities.iter().map(Capability::name).collect();
+    put_tagged(
+        dst,
+        "HELLO",
+        format!("{version}\r{}", capabilities.join(",")).as_bytes(),
+    );
+}
+
+fn decode_hello(payload: &[u8]) -> Result<(u32, Vec<Capability>), ProtocolError> {
+    let fields = fields(payload)?;
+    let version = parse_field(fields.first())?;
+    let capabilities = fields
+        .get(1)
+        .map(|capabilities| {
+            capabilities
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(Capability::from_name)
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((version, capabilities))
+}
+
+/// Sent by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Must be the first frame of every session.
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Must follow the `Hello`. The server answers with the listing of the root,
+    /// or an `Error` after which the client may try again.
+    Login {
+        username: String,
+        password: String,
+    },
+    File(String),
+    Dir(String),
+    SaveDir(String),
+    FileInfo(String),
+    /// Like `File` but only `length` bytes (or everything) starting at `offset`.
+    ReadRange {
+        path: String,
+        offset: u64,
+        length: Option<u64>,
+    },
+    /*
+    Uploads mirror downloads: `Put` names the destination (relative paths are
+    taken from the directory the session is in), the server answers `Ready`
+    or an `Error`, then the client streams `Data` chunks and a `TransferEnd`
+    with the SHA-256 of the uncompressed content, or `Abort`s. The server
+    finishes with `Done` or an `Error`. `PutDir` works the same with a tar of
+    the directory as the content, unpacked into `path`.
+    */
+    Put {
+        path: String,
+        overwrite: bool,
+        compression: Compression,
+    },
+    PutDir {
+        path: String,
+        existing: Existing,
+        compression: Compression,
+    },
+    Data(Vec<u8>),
+    TransferEnd {
+        sha256: String,
+    },
+    Abort,
+    /// Changes to the remote tree, answered with `Done` or an `Error`. Relative
+    /// paths are taken from the directory the session is in.
+    Mkdir(String),
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Directories are only deleted with their content if `recursive` is set.
+    Delete {
+        path: String,
+        recursive: bool,
+    },
+    Shutdown,
+}
+
+impl Request {
+    /// The tag it is sent with, for logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "HELLO",
+            Request::Login { .. } => "LOGIN",
+            Request::File(_) => "FILE",
+            Request::Dir(_) => "DIR",
+            Request::SaveDir(_) => "SAVEDIR",
+            Request::FileInfo(_) => "FILEINFO",
+            Request::ReadRange { .. } => "READRANGE",
+            Request::Put { .. } => "PUT",
+            Request::PutDir { .. } => "PUTDIR",
+            Request::Data(_) => "DATA",
+            Request::TransferEnd { .. } => "TRANSFEREND",
+            Request::Abort => "ABORT",
+            Request::Mkdir(_) => "MKDIR",
+            Request::Rename { .. } => "RENAME",
+            Request::Delete { .. } => "DELETE",
+            Request::Shutdown => "SHUTDOWN",
+        }
+    }
+    /// The remote path the request is about, the source for a `Rename`.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Request::File(path)
+            | Request::Dir(path)
+            | Request::SaveDir(path)
+            | Request::FileInfo(path)
+            | Request::ReadRange { path, .. }
+            | Request::Put { path, .. }
+            | Request::PutDir { path, .. }
+            | Request::Mkdir(path)
+            | Request::Rename { from: path, .. }
+            | Request::Delete { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Sent by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Carries the negotiated capabilities.
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// The client's `Hello` asked for a version this server does not speak.
+    UnsupportedVersion {
+        supported: u32,
+    },
+    /*
+    Paths on the wire are relative to the served root, which is `/`. The real
+    location of the root on the server never leaves it.
+    */
+    /// The directory the session is in now, what the user may do in it and what
+    /// is in it. Entries the user may not see are left out.
+    Listing {
+        path: String,
+        access: Access,
+        entries: Vec<Entry>,
+    },
+    /// Entries of a listing too big for one frame, sent ahead of its `Listing`
+    /// which holds the rest. `read_response` puts them back together.
+    ListingPart(Vec<Entry>),
+    /*
+    Downloads (FILE_ and SAVEDIR_) are streamed: a `Transfer` announcing the size
+    if it is known up front, any number of `Data` chunks of at most CHUNK_SIZE
+    bytes and a closing `TransferEnd`. An `Error` in place of any of those
+    aborts the transfer.
+    The SHA-256 in `TransferEnd` covers the file from its first byte up to the
+    last byte sent, so for a `ReadRange` it includes the part the client
+    already has. Both the size and the checksum are about the uncompressed
+    content, only the `Data` chunks are compressed.
+    */
+    Transfer {
+        size: Option<u64>,
+        compression: Compression,
+    },
+    Data(Vec<u8>),
+    TransferEnd {
+        sha256: String,
+    },
+    FileInfo {
+        is_file: bool,
+        size: u64,
+        access: Access,
+    },
+    /// The server is waiting for the data of a `Put`.
+    Ready,
+    /// A `Put`, `PutDir` or change to the remote tree went through.
+    Done,
+    /// The request failed, the session stays usable.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Response::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Response {
+    fn from(error: std::io::Error) -> Self {
+        Response::error(error.kind().into(), error)
+    }
+}
+
+pub trait Message: Sized {
+    fn encode(&self, dst: &mut BytesMut);
+    fn decode(body: BytesMut) -> Result<Self, ProtocolError>;
+    /// The frames the message is sent as, payloads that can get large are split so
+    /// that each frame stays around CHUNK_SIZE.
+    fn into_frames(self) -> Vec<Self> {
+        vec![self]
+    }
+}
+
+/// Cuts a `Data` payload into CHUNK_SIZE pieces, compressed output can be larger
+/// than the chunk that went in.
+fn split_data<M>(data: Vec<u8>, frame: fn(Vec<u8>) -> M) -> Vec<M> {
+    if data.len() <= CHUNK_SIZE {
+        return vec![frame(data)];
+    }
+    data.chunks(CHUNK_SIZE)
+        .map(|piece| frame(piece.to_vec()))
+        .collect()
+}
+
+fn encode_entries(entries: &[Entry]) -> impl Iterator<Item = String> + '_ {
+    entries.iter().map(|entry| {
+        let access = entry.access.name();
+        match entry.kind {
+            EntryKind::Dir => format!("DIR_{access}_{}", entry.path),
+            EntryKind::File => format!("FILE_{access}_{}", entry.path),
+        }
+    })
+}
+
+fn decode_entries(fields: impl Iterator<Item = String>) -> Result<Vec<Entry>, ProtocolError> {
+    let mut entries = vec![];
+    for entry in fields.filter(|e| !e.is_empty()) {
+        let (kind, rest) = entry.split_once('_').unwrap_or_default();
+        let (access, path) = rest.split_once('_').unwrap_or_default();
+        let access = parse_access(Some(access))?;
+        match kind {
+            "DIR" => entries.push(Entry::dir(path).with_access(access)),
+            "FILE" => entries.push(Entry::file(path).with_access(access)),
+            _ => return Err(ProtocolError::Malformed(format!("bad entry {entry:?}"))),
+        }
+    }
+    Ok(entries)
+}
+
+fn put_tagged(dst: &mut BytesMut, tag: &str, payload: &[u8]) {
+    dst.put_slice(tag.as_bytes());
+    dst.put_u8(TAG_SEPARATOR);
+    dst.put_slice(payload);
+}
+
+fn split_tag(body: &BytesMut) -> (&[u8], &[u8]) {
+    match body.iter().position(|byte| *byte == TAG_SEPARATOR) {
+        Some(position) => (&body[..position], &body[position + 1..]),
+        None => (&body[..], &[]),
+    }
+}
+
+fn payload_str(payload: &[u8]) -> Result<String, ProtocolError> {
+    String::from_utf8(payload.to_vec())
+        .map_err(|_| ProtocolError::Malformed("payload is not valid utf-8".to_string()))
+}
+
+fn fields(payload: &[u8]) -> Result<Vec<String>, ProtocolError> {
+    Ok(payload_str(payload)?
+        .split(SEPARATOR as char)
+        .map(str::to_string)
+        .collect())
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&String>) -> Result<T, ProtocolError> {
+    field
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| ProtocolError::Malformed(format!("bad field {field:?}")))
+}
+
+fn parse_access(field: Option<&str>) -> Result<Access, ProtocolError> {
+    field
+        .and_then(Access::from_name)
+        .ok_or_else(|| ProtocolError::Malformed(format!("bad access {field:?}")))
+}
+
+impl Message for Request {
+    fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Request::Hello {
+                version,
+                capabilities,
+            } => encode_hello(dst, *version, capabilities),
+            Request::Login { username, password } => {
+                put_tagged(dst, "LOGIN", format!("{username}\r{password}").as_bytes())
+            }
+            Request::File(path) => put_tagged(dst, "FILE", path.as_bytes()),
+            Request::Dir(path) => put_tagged(dst, "DIR", path.as_bytes()),
+            Request::SaveDir(path) => put_tagged(dst, "SAVEDIR", path.as_bytes()),
+            Request::FileInfo(path) => put_tagged(dst, "FILEINFO", path.as_bytes()),
+            Request::ReadRange {
+                path,
+                offset,
+                length,
+            } => {
+                let length = length.map(|length| length.to_string()).unwrap_or_default();
+                put_tagged(
+                    dst,
+                    "READRANGE",
+                    format!("{offset}\r{length}\r{path}").as_bytes(),
+                )
+            }
+            Request::Put {
+                path,
+                overwrite,
+                compression,
+            } => put_tagged(
+                dst,
+                "PUT",
+                format!("{}\r{}\r{path}", *overwrite as u8, compression.name()).as_bytes(),
+            ),
+            Request::PutDir {
+                path,
+                existing,
+                compression,
+            } => put_tagged(
+                dst,
+                "PUTDIR",
+                format!("{}\r{}\r{path}", existing.name(), compression.name()).as_bytes(),
+            ),
+            Request::Data(chunk) => put_tagged(dst, "DATA", chunk),
+            Request::TransferEnd { sha256 } => put_tagged(dst, "TRANSFEREND", sha256.as_bytes()),
+            Request::Abort => dst.put_slice(b"ABORT"),
+            Request::Mkdir(path) => put_tagged(dst, "MKDIR", path.as_bytes()),
+            Request::Rename { from, to } => {
+                put_tagged(dst, "RENAME", format!("{from}\r{to}").as_bytes())
+            }
+            Request::Delete { path, recursive } => put_tagged(
+                dst,
+                "DELETE",
+                format!("{}\r{path}", *recursive as u8).as_bytes(),
+            ),
+            Request::Shutdown => dst.put_slice(b"SHUTDOWN"),
+        }
+    }
+    fn decode(body: BytesMut) -> Result<Self, ProtocolError> {
+        let (tag, payload) = split_tag(&body);
+        Ok(match tag {
+            b"HELLO" => {
+                let (version, capabilities) = decode_hello(payload)?;
+                Request::Hello {
+                    version,
+                    capabilities,
+                }
+            }
+            b"LOGIN" => {
+                let payload = payload_str(payload)?;
+                let (username, password) = payload
+                    .split_once(SEPARATOR as char)
+                    .ok_or_else(|| ProtocolError::Malformed("missing password".to_string()))?;
+                Request::Login {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }
+            }
+            b"FILE" => Request::File(payload_str(payload)?),
+            b"DIR" => Request::Dir(payload_str(payload)?),
+            b"SAVEDIR" => Request::SaveDir(payload_str(payload)?),
+            b"FILEINFO" => Request::FileInfo(payload_str(payload)?),
+            b"READRANGE" => {
+                let payload = payload_str(payload)?;
+                let mut fields = payload.splitn(3, SEPARATOR as char).map(str::to_string);
+                let offset = parse_field(fields.next().as_ref())?;
+                let length = match fields.next() {
+                    Some(length) if length.is_empty() => None,
+                    length => Some(parse_field(length.as_ref())?),
+                };
+                Request::ReadRange {
+                    path: fields
+                        .next()
+                        .ok_or_else(|| ProtocolError::Malformed("missing path".to_string()))?,
+                    offset,
+                    length,
+                }
+            }
+            b"PUT" => {
+                let payload = payload_str(payload)?;
+                let mut fields = payload.splitn(3, SEPARATOR as char).map(str::to_string);
+                let overwrite = parse_field::<u8>(fields.next().as_ref())? == 1;
+                let compression = fields
+                    .next()
+                    .and_then(|name| Compression::from_name(&name))
+                    .ok_or_else(|| ProtocolError::Malformed("bad compression".to_string()))?;
+                Request::Put {
+                    path: fields
+                        .next()
+                        .ok_or_else(|| ProtocolError::Malformed("missing path".to_string()))?,
+                    overwrite,
+                    compression,
+                }
+            }
+            b"PUTDIR" => {
+                let payload = payload_str(payload)?;
+                let mut fields = payload.splitn(3, SEPARATOR as char).map(str::to_string);
+                let existing = fields
+                    .next()
+                    .and_then(|name| Existing::from_name(&name))
+                    .ok_or_else(|| ProtocolError::Malformed("bad existing policy".to_string()))?;
+                let compression = fields
+                    .next()
+                    .and_then(|name| Compression::from_name(&name))
+                    .ok_or_else(|| ProtocolError::Malformed("bad compression".to_string()))?;
+                Request::PutDir {
+                    path: fields
+                        .next()
+                        .ok_or_else(|| ProtocolError::Malformed("missing path".to_string()))?,
+                    existing,
+                    compression,
+                }
+            }
+            b"DATA" => Request::Data(payload.to_vec()),
+            b"TRANSFEREND" => Request::TransferEnd {
+                sha256: payload_str(payload)?,
+            },
+            b"ABORT" => Request::Abort,
+            b"MKDIR" => Request::Mkdir(payload_str(payload)?),
+            b"RENAME" => {
+                let payload = payload_str(payload)?;
+                let (from, to) = payload
+                    .split_once(SEPARATOR as char)
+                    .ok_or_else(|| ProtocolError::Malformed("missing destination".to_string()))?;
+                Request::Rename {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                }
+            }
+            b"DELETE" => {
+                let payload = payload_str(payload)?;
+                let mut fields = payload.splitn(2, SEPARATOR as char).map(str::to_string);
+                let recursive = parse_field::<u8>(fields.next().as_ref())? == 1;
+                Request::Delete {
+                    path: fields
+                        .next()
+                        .ok_or_else(|| ProtocolError::Malformed("missing path".to_string()))?,
+                    recursive,
+                }
+            }
+            b"SHUTDOWN" => Request::Shutdown,
+            tag => {
+                return Err(ProtocolError::UnknownTag(
+                    String::from_utf8_lossy(tag).into(),
+                ))
+            }
+        })
+    }
+    fn into_frames(self) -> Vec<Self> {
+        match self {
+            Request::Data(data) => split_data(data, Request::Data),
+            request => vec![request],
+        }
+    }
+}
+
+impl Message for Response {
+    fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Response::Hello {
+                version,
+                capabilities,
+            } => encode_hello(dst, *version, capabilities),
+            Response::UnsupportedVersion { supported } => {
+                put_tagged(dst, "UNSUPPORTEDVERSION", supported.to_string().as_bytes())
+            }
+            Response::Listing {
+                path,
+                access,
+                entries,
+            } => {
+                let fields: Vec<String> = [path.clone(), access.name().to_string()]
+                    .into_iter()
+                    .chain(encode_entries(entries))
+                    .collect();
+                put_tagged(dst, "LISTING", fields.join("\r").as_bytes());
+            }
+            Response::ListingPart(entries) => {
+                let fields: Vec<String> = encode_entries(entries).collect();
+                put_tagged(dst, "LISTINGPART", fields.join("\r").as_bytes());
+            }
+            Response::Transfer { size, compression } => {
+                let size = size.map(|size| size.to_string()).unwrap_or_default();
+                put_tagged(
+                    dst,
+                    "TRANSFER",
+                    format!("{size}\r{}", compression.name()).as_bytes(),
+                )
+            }
+            Response::Data(chunk) => put_tagged(dst, "DATA", chunk),
+            Response::TransferEnd { sha256 } => put_tagged(dst, "TRANSFEREND", sha256.as_bytes()),
+            Response::FileInfo {
+                is_file,
+                size,
+                access,
+            } => put_tagged(
+                dst,
+                "FILEINFO",
+                format!("{}\r{size}\r{}", *is_file as u8, access.name()).as_bytes(),
+            ),
+            Response::Ready => dst.put_slice(b"READY"),
+            Response::Done => dst.put_slice(b"DONE"),
+            Response::Error { code, message } => {
+                put_tagged(dst, "ERROR", format!("{code}\r{message}").as_bytes())
+            }
+        }
+    }
+    fn decode(body: BytesMut) -> Result<Self, ProtocolError> {
+        let (tag, payload) = split_tag(&body);
+        Ok(match tag {
+            b"HELLO" => {
+                let (version, capabilities) = decode_hello(payload)?;
+                Response::Hello {
+                    version,
+                    capabilities,
+                }
+            }
+            b"UNSUPPORTEDVERSION" => Response::UnsupportedVersion {
+                supported: parse_field(Some(&payload_str(payload)?))?,
+            },
+            b"LISTING" => {
+                let mut fields = fields(payload)?.into_iter();
+                let path = fields
+                    .next()
+                    .ok_or_else(|| ProtocolError::Malformed("missing path".to_string()))?;
+                let access = parse_access(fields.next().as_deref())?;
+                Response::Listing {
+                    path,
+                    access,
+                    entries: decode_entries(fields)?,
+                }
+            }
+            b"LISTINGPART" => Response::ListingPart(decode_entries(fields(payload)?.into_iter())?),
+            b"TRANSFER" => {
+                let fields = fields(payload)?;
+                Response::Transfer {
+                    size: match fields.first() {
+                        Some(size) if size.is_empty() => None,
+                        size => Some(parse_field(size)?),
+                    },
+                    compression: fields
+                        .get(1)
+                        .and_then(|name| Compression::from_name(name))
+                        .ok_or_else(|| {
+                            ProtocolError::Malformed(format!("bad compression {:?}", fields.get(1)))
+                        })?,
+                }
+            }
+            b"DATA" => Response::Data(payload.to_vec()),
+            b"TRANSFEREND" => Response::TransferEnd {
+                sha256: payload_str(payload)?,
+            },
+            b"FILEINFO" => {
+                let fields = fields(payload)?;
+                Response::FileInfo {
+                    is_file: parse_field::<u8>(fields.first())? == 1,
+                    size: parse_field(fields.get(1))?,
+                    access: parse_access(fields.get(2).map(String::as_str))?,
+                }
+            }
+            b"READY" => Response::Ready,
+            b"DONE" => Response::Done,
+            b"ERROR" => {
+                let payload = payload_str(payload)?;
+                let (code, message) = payload.split_once('\r').unwrap_or((&payload, ""));
+                Response::Error {
+                    code: ErrorCode::from_name(code),
+                    message: message.to_string(),
+                }
+            }
+            tag => {
+                return Err(ProtocolError::UnknownTag(
+                    String::from_utf8_lossy(tag).into(),
+                ))
+            }
+        })
+    }
+    fn into_frames(self) -> Vec<Self> {
+        match self {
+            Response::Data(data) => split_data(data, Response::Data),
+            Response::Listing {
+                path,
+                access,
+                entries,
+            } => {
+                let mut frames = vec![];
+                let mut part = vec![];
+                let mut size = 0;
+                for entry in entries {
+                    // The path plus the longest kind and access prefix
+                    let entry_size = entry.path.len() + 16;
+                    if size + entry_size > CHUNK_SIZE && !part.is_empty() {
+                        frames.push(Response::ListingPart(std::mem::take(&mut part)));
+                        size = 0;
+                    }
+                    size += entry_size;
+                    part.push(entry);
+                }
+                frames.push(Response::Listing {
+                    path,
+                    access,
+                    entries: part,
+                });
+                frames
+            }
+            response => vec![response],
+        }
+    }
+}
+
+/// Frames `Message`s as `<len>\r<body>`, decoding `T` and encoding any message.
+pub struct MessageCodec<T> {
+    frame_length: Option<usize>,
+    max_frame_size: usize,
+    _decodes: PhantomData<fn() -> T>,
+}
+
+/// Used by the server: decodes requests, encodes responses.
+pub type ServerCodec = MessageCodec<Request>;
+/// Used by the client: decodes responses, encodes requests.
+pub type ClientCodec = MessageCodec<Response>;
+
+impl<T> MessageCodec<T> {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+    /// A codec refusing frames with a body larger than `max_frame_size` bytes, both
+    /// ways. It should not be below MIN_FRAME_SIZE.
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        MessageCodec {
+            frame_length: None,
+            max_frame_size,
+            _decodes: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for MessageCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Message> Decoder for MessageCodec<T> {
+    type Item = T;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T>, ProtocolError> {
+        let frame_length = match self.frame_length {
+            Some(frame_length) => frame_length,
+            None => {
+                let position = src.iter().position(|byte| *byte == SEPARATOR);
+                // Checked before the separator shows up, so garbage can't pile up
+                let header = &src[..position.unwrap_or(src.len())];
+                let invalid =
+                    || ProtocolError::InvalidLength(String::from_utf8_lossy(header).into_owned());
+                if header.len() > MAX_LENGTH_DIGITS || !header.iter().all(u8::is_ascii_digit) {
+                    return Err(invalid());
+                }
+                let Some(position) = position else {
+                    return Ok(None);
+                };
+                let frame_length: usize = std::str::from_utf8(header)
+                    .ok()
+                    .and_then(|header| header.parse().ok())
+                    .ok_or_else(invalid)?;
+                if frame_length > self.max_frame_size {
+                    return Err(ProtocolError::FrameTooLarge {
+                        length: frame_length,
+                        max: self.max_frame_size,
+                    });
+                }
+                src.advance(position + 1);
+                self.frame_length = Some(frame_length);
+                frame_length
+            }
+        };
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+        self.frame_length = None;
+        T::decode(src.split_to(frame_length)).map(Some)
+    }
+}
+
+impl<T, M: Message> Encoder<M> for MessageCodec<T> {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: M, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        for frame in message.into_frames() {
+            let mut body = BytesMut::new();
+            frame.encode(&mut body);
+            if body.len() > self.max_frame_size {
+                return Err(ProtocolError::FrameTooLarge {
+                    length: body.len(),
+                    max: self.max_frame_size,
+                });
+            }
+            dst.reserve(body.len() + MAX_LENGTH_DIGITS + 1);
+            dst.put_slice(format!("{}", body.len()).as_bytes());
+            dst.put_u8(SEPARATOR);
+            dst.put_slice(&body);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut ClientCodec, src: &mut BytesMut) -> Vec<Response> {
+        let mut responses = vec![];
+        while let Some(response) = codec.decode(src).unwrap() {
+            responses.push(response);
+        }
+        responses
+    }
+
+    #[test]
+    fn rejects_lengths_that_are_not_numbers() {
+        let mut codec = ServerCodec::new();
+        for header in ["12a\r", "-1\r", "\r", "DIR_/\r", "999999999999999999999"] {
+            let mut src = BytesMut::from(header);
+            assert!(matches!(
+                codec.decode(&mut src),
+                Err(ProtocolError::InvalidLength(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_frames_above_the_limit_before_reading_them() {
+        let mut codec = ServerCodec::with_max_frame_size(MIN_FRAME_SIZE);
+        let mut src = BytesMut::from("99999999999\r");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(ProtocolError::FrameTooLarge {
+                length: 99999999999,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn splits_large_payloads_into_frames_within_the_limit() {
+        let mut codec = ClientCodec::with_max_frame_size(MIN_FRAME_SIZE);
+        let entries: Vec<Entry> = (0..20_000)
+            .map(|index| Entry::file(format!("/some/longer/directory/file{index}")))
+            .collect();
+        let mut src = BytesMut::new();
+        let listing = Response::Listing {
+            path: "/".to_string(),
+            access: Access::Read,
+            entries: entries.clone(),
+        };
+        codec.encode(listing, &mut src).unwrap();
+        codec
+            .encode(Response::Data(vec![7; 3 * CHUNK_SIZE + 1]), &mut src)
+            .unwrap();
+
+        let responses = decode_all(&mut codec, &mut src);
+        let mut decoded = vec![];
+        let mut data = vec![];
+        for response in responses {
+            match response {
+                Response::ListingPart(part) => decoded.extend(part),
+                Response::Listing { entries, .. } => decoded.extend(entries),
+                Response::Data(chunk) => {
+                    assert!(chunk.len() <= CHUNK_SIZE);
+                    data.extend(chunk);
+                }
+                response => panic!("unexpected {response:?}"),
+            }
+        }
+        assert_eq!(decoded, entries);
+        assert_eq!(data, vec![7; 3 * CHUNK_SIZE + 1]);
+    }
+
+    #[test]
+    fn caps_listings_of_entries_with_empty_paths() {
+        let mut codec = ClientCodec::new();
+        let part = vec![Entry::file(""); 1_000];
+        let mut src = BytesMut::new();
+        for _ in 0..10 {
+            codec
+                .encode(Response::ListingPart(part.clone()), &mut src)
+                .unwrap();
+        }
+        let max_size = 50 * 1024;
+        let mut parts = ListingParts::new(max_size);
+        let mut refused = false;
+        for response in decode_all(&mut codec, &mut src) {
+            let Response::ListingPart(entries) = response else {
+                panic!("unexpected {response:?}");
+            };
+            assert_eq!(entries.len(), part.len());
+            if let Err(error) = parts.add(entries) {
+                assert!(matches!(error, ProtocolError::ListingTooLarge));
+                refused = true;
+                break;
+            }
+        }
+        assert!(refused);
+        let kept = parts.finish(vec![]).len();
+        assert!(kept * std::mem::size_of::<Entry>() <= max_size);
+    }
+}